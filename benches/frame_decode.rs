@@ -0,0 +1,92 @@
+//! Compares reading frames from ffmpeg as BMP images against reading raw RGB into a reused
+//! buffer. Run with `cargo bench --bench frame_decode`.
+
+use std::hint::black_box;
+use std::io::{Cursor, Read};
+use std::time::{Duration, Instant};
+
+use image::codecs::bmp::BmpEncoder;
+use image::io::Reader as ImageReader;
+use image::{ColorType, Rgb, RgbImage};
+
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 720;
+const FRAMES: usize = 120;
+
+fn test_frame(n: usize) -> RgbImage {
+    RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        Rgb([(x as usize + n) as u8, (y as usize + n) as u8, (x ^ y) as u8])
+    })
+}
+
+/// The old way, as `image2pipe` with the BMP codec.
+fn bmp_stream() -> Vec<u8> {
+    let mut stream = Vec::new();
+    for n in 0..FRAMES {
+        let frame = test_frame(n);
+        BmpEncoder::new(&mut stream)
+            .encode(&frame, WIDTH, HEIGHT, ColorType::Rgb8)
+            .unwrap();
+    }
+    stream
+}
+
+/// The new way, as `rawvideo` with `rgb24` pixels.
+fn raw_stream() -> Vec<u8> {
+    (0..FRAMES).flat_map(|n| test_frame(n).into_raw()).collect()
+}
+
+fn read_bmp_frames(stream: &mut impl Read) -> usize {
+    let mut frames = 0;
+    loop {
+        let mut start: [u8; 6] = [0; 6];
+        if stream.read_exact(&mut start).is_err() {
+            return frames;
+        }
+        let bmp_length = u32::from_le_bytes(start[2..6].try_into().unwrap());
+        let mut remaining_bytes: Vec<u8> = vec![0; (bmp_length - 6) as usize];
+        stream.read_exact(&mut remaining_bytes).unwrap();
+        let image_bytes: Vec<u8> = start.into_iter().chain(remaining_bytes).collect();
+        let frame = ImageReader::with_format(Cursor::new(image_bytes), image::ImageFormat::Bmp)
+            .decode()
+            .unwrap()
+            .to_rgb8();
+        black_box(frame);
+        frames += 1;
+    }
+}
+
+fn read_raw_frames(stream: &mut impl Read) -> usize {
+    let mut frames = 0;
+    let mut frame = RgbImage::new(WIDTH, HEIGHT);
+    loop {
+        let buffer: &mut [u8] = &mut frame;
+        if stream.read_exact(buffer).is_err() {
+            return frames;
+        }
+        black_box(&frame);
+        frames += 1;
+    }
+}
+
+fn bench(name: &str, stream: &[u8], read: impl Fn(&mut Cursor<&[u8]>) -> usize) -> Duration {
+    // The best of a few runs, to keep other things on the machine out of it
+    let per_frame = (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            let frames = read(&mut Cursor::new(stream));
+            assert_eq!(frames, FRAMES);
+            start.elapsed() / FRAMES as u32
+        })
+        .min()
+        .unwrap();
+    println!("{:>4}: {:?}/frame", name, per_frame);
+    per_frame
+}
+
+fn main() {
+    println!("{} frames at {}x{}", FRAMES, WIDTH, HEIGHT);
+    let bmp = bench("bmp", &bmp_stream(), |stream| read_bmp_frames(stream));
+    let raw = bench("raw", &raw_stream(), |stream| read_raw_frames(stream));
+    println!("raw is {:.1}x faster", bmp.as_secs_f64() / raw.as_secs_f64());
+}
@@ -0,0 +1,292 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::export::Exporter;
+use crate::html::CssPalette;
+use crate::palette::nearest_ansi16;
+use crate::recording::Header;
+use crate::renderers::get_quarters_char;
+use crate::screen::{Frame, WIDE_TAIL};
+
+/// Code page 437 from 0x80 up, which is where it differs from ASCII.
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+const SHADE_LIGHT: u8 = 0xb0;
+const SHADE_MEDIUM: u8 = 0xb1;
+const SHADE_DARK: u8 = 0xb2;
+
+/// The font named in the SAUCE record, which is the usual 8x16 VGA one.
+const SAUCE_FONT: &str = "IBM VGA";
+
+/// Encodes a character in code page 437. Quarter blocks, which it doesn't have, become the half
+/// block or shade that covers about as much of the cell, and anything else becomes `?`.
+pub(crate) fn cp437(c: char) -> u8 {
+    if (' '..='~').contains(&c) {
+        return c as u8;
+    }
+    if let Some(i) = CP437_HIGH.chars().position(|high| high == c) {
+        return 0x80 + i as u8;
+    }
+
+    let quarters = (0..16)
+        .map(|bits| (bits & 8 != 0, bits & 4 != 0, bits & 2 != 0, bits & 1 != 0))
+        .find(|subpixels| get_quarters_char(*subpixels) == c);
+    match quarters {
+        Some((tl, tr, bl, br)) => match [tl, tr, bl, br].iter().filter(|s| **s).count() {
+            1 => SHADE_LIGHT,
+            // The halves are all in the table, so this is one of the diagonals
+            2 => SHADE_MEDIUM,
+            _ => SHADE_DARK,
+        },
+        None => b'?',
+    }
+}
+
+/// Turns a frame into ANSI art: CP437 text, colored with the 16 colors of SGR. Bright
+/// backgrounds use the blink attribute, as iCE color.
+fn encode_frame(frame: &Frame, palette: &CssPalette, out: &mut Vec<u8>) {
+    for row in &frame.cells {
+        let mut current = None;
+        for cell in row {
+            let (fg, bg) = palette.cell_colors(cell);
+            let colors = (nearest_ansi16(&fg), nearest_ansi16(&bg));
+            if current != Some(colors) {
+                let (fg, bg) = colors;
+                out.extend_from_slice(b"\x1b[0");
+                if fg >= 8 {
+                    out.extend_from_slice(b";1");
+                }
+                if bg >= 8 {
+                    out.extend_from_slice(b";5");
+                }
+                out.extend_from_slice(format!(";{};{}m", 30 + fg % 8, 40 + bg % 8).as_bytes());
+                current = Some(colors);
+            }
+            // The second half of a wide character, which was already written as a `?`
+            out.push(if cell.ch == WIDE_TAIL { b' ' } else { cp437(cell.ch) });
+        }
+        out.extend_from_slice(b"\x1b[0m\r\n");
+    }
+}
+
+/// The 128 byte SAUCE record that ends an ANSI art file, along with the EOF character before it.
+fn sauce(header: &Header, author: &str, file_size: usize) -> Vec<u8> {
+    let field = |text: &str, len: usize, pad: u8| {
+        let mut bytes = text.chars().map(cp437).take(len).collect::<Vec<u8>>();
+        bytes.resize(len, pad);
+        bytes
+    };
+
+    let mut record = vec![0x1a];
+    record.extend_from_slice(b"SAUCE00");
+    record.extend(field(header.title.as_deref().unwrap_or(""), 35, b' '));
+    record.extend(field(author, 20, b' '));
+    record.extend(field("", 20, b' '));
+    record.extend(field(&today(), 8, b' '));
+    record.extend((file_size as u32).to_le_bytes());
+    // Character data, in the ANSi file type
+    record.extend([1, 1]);
+    record.extend((header.width as u16).to_le_bytes());
+    record.extend((header.height as u16).to_le_bytes());
+    record.extend([0; 4]);
+    // No comments, then iCE color and 8 pixel wide characters
+    record.extend([0, 0b0000_0011]);
+    record.extend(field(SAUCE_FONT, 22, 0));
+    record
+}
+
+/// Today's date as `CCYYMMDD`, for SAUCE.
+fn today() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() / 86400) as i64;
+    date(days)
+}
+
+/// The date `days` days after 1970-01-01 as `CCYYMMDD`.
+fn date(days: i64) -> String {
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// Writes the whole clip as one ANSImation, with each frame drawn over the last from the top
+/// left corner.
+pub(crate) struct AnsWriter {
+    file: BufWriter<File>,
+    header: Header,
+    author: String,
+    palette: CssPalette,
+    written: usize,
+}
+
+impl AnsWriter {
+    pub(crate) fn create(path: &str, header: &Header, author: &str) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            header: header.clone(),
+            author: author.to_string(),
+            palette: CssPalette::new(),
+            written: 0,
+        };
+        writer.write(b"\x1b[0m\x1b[2J")?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.written += bytes.len();
+        Ok(())
+    }
+}
+
+impl Exporter for AnsWriter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        self.palette.update(&frame.prelude);
+        let mut out = b"\x1b[H".to_vec();
+        encode_frame(&frame, &self.palette, &mut out);
+        self.write(&out)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let record = sauce(&self.header, &self.author, self.written);
+        self.file.write_all(&record)?;
+        self.file.flush()
+    }
+}
+
+/// Writes each frame to its own `.ans` file in a directory, named by frame number.
+pub(crate) struct AnsFramesWriter {
+    dir: PathBuf,
+    header: Header,
+    author: String,
+    palette: CssPalette,
+    frames: usize,
+}
+
+impl AnsFramesWriter {
+    pub(crate) fn create(path: &str, header: &Header, author: &str) -> io::Result<Self> {
+        fs::create_dir_all(path)?;
+        Ok(Self {
+            dir: PathBuf::from(path),
+            header: header.clone(),
+            author: author.to_string(),
+            palette: CssPalette::new(),
+            frames: 0,
+        })
+    }
+}
+
+impl Exporter for AnsFramesWriter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        self.palette.update(&frame.prelude);
+        let mut out = Vec::new();
+        encode_frame(&frame, &self.palette, &mut out);
+        out.extend(sauce(&self.header, &self.author, out.len()));
+
+        let path = self.dir.join(format!("{:05}.ans", self.frames));
+        self.frames += 1;
+        fs::write(path, out)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cp437_chars() {
+        assert_eq!(" Az~".chars().map(cp437).collect::<Vec<u8>>(), b" Az~");
+        assert_eq!(
+            "█▀▄▌▐░▒▓".chars().map(cp437).collect::<Vec<u8>>(),
+            [0xdb, 0xdf, 0xdc, 0xdd, 0xde, 0xb0, 0xb1, 0xb2]
+        );
+        assert_eq!(cp437('é'), 0x82);
+        assert_eq!(cp437('\u{a0}'), 0xff);
+        // Quarter blocks go by how much of the cell they cover
+        assert_eq!(cp437('▘'), SHADE_LIGHT);
+        assert_eq!(cp437('▗'), SHADE_LIGHT);
+        assert_eq!(cp437('▚'), SHADE_MEDIUM);
+        assert_eq!(cp437('▞'), SHADE_MEDIUM);
+        assert_eq!(cp437('▛'), SHADE_DARK);
+        assert_eq!(cp437('▟'), SHADE_DARK);
+        assert_eq!(cp437('€'), b'?');
+        assert_eq!(cp437('\t'), b'?');
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "19700101");
+        assert_eq!(date(-1), "19691231");
+        assert_eq!(date(11016), "20000229");
+        assert_eq!(date(20743), "20261017");
+        assert_eq!(today().len(), 8);
+    }
+
+    #[test]
+    fn sauce_record() {
+        let header = Header {
+            framerate: 10,
+            width: 80,
+            height: 25,
+            renderer: None,
+            color_depth: None,
+            char_height: None,
+            title: Some("Café".to_string()),
+            duration: None,
+            keyframe_interval: None,
+        };
+        let record = sauce(&header, "Someone", 1234);
+        assert_eq!(record.len(), 129);
+        assert_eq!(record[0], 0x1a);
+
+        // The offsets from the SAUCE spec start after the EOF character
+        let record = &record[1..];
+        assert_eq!(&record[0..7], b"SAUCE00");
+        assert_eq!(&record[7..42], [&b"Caf\x82"[..], &[b' '; 31]].concat());
+        assert_eq!(&record[42..62], [&b"Someone"[..], &[b' '; 13]].concat());
+        assert_eq!(&record[62..82], [b' '; 20]);
+        assert!(record[82..90].iter().all(u8::is_ascii_digit));
+        assert_eq!(record[90..94], 1234u32.to_le_bytes());
+        assert_eq!(record[94..96], [1, 1]);
+        assert_eq!(record[96..98], 80u16.to_le_bytes());
+        assert_eq!(record[98..100], 25u16.to_le_bytes());
+        assert_eq!(record[100..104], [0; 4]);
+        assert_eq!(record[104..106], [0, 0b0000_0011]);
+        assert_eq!(&record[106..128], [&b"IBM VGA"[..], &[0; 15]].concat());
+    }
+
+    #[test]
+    fn sauce_long_title() {
+        let header = Header {
+            framerate: 10,
+            width: 40,
+            height: 12,
+            renderer: None,
+            color_depth: None,
+            char_height: None,
+            title: Some("x".repeat(50)),
+            duration: None,
+            keyframe_interval: None,
+        };
+        let record = sauce(&header, &"y".repeat(30), 0);
+        assert_eq!(record.len(), 129);
+        assert_eq!(&record[8..43], [b'x'; 35]);
+        assert_eq!(&record[43..63], [b'y'; 20]);
+    }
+}
@@ -0,0 +1,472 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+/// The format ffmpeg is asked to decode audio to: interleaved signed 16-bit stereo.
+pub(crate) const SAMPLE_RATE: u32 = 48000;
+pub(crate) const CHANNELS: u16 = 2;
+const BYTES_PER_FRAME: usize = CHANNELS as usize * 2;
+
+/// How much audio is read and played at a time, which is also how often the clock moves.
+const CHUNK_FRAMES: usize = SAMPLE_RATE as usize / 100;
+
+/// How much the sound servers are asked to buffer, so that it can be taken off the clock.
+const SINK_LATENCY: Duration = Duration::from_millis(50);
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum AudioBackend {
+    /// Play through PulseAudio (or PipeWire) with `pacat`
+    Pulse,
+    /// Play through ALSA with `aplay`
+    Alsa,
+    /// Throw the audio away, but keep time as if it was playing
+    Null,
+    /// Write the audio to a WAV file
+    Wav,
+}
+
+/// Where audio goes, shared by every source that gets opened while the player runs.
+#[derive(Clone)]
+pub(crate) struct AudioOutput {
+    backend: AudioBackend,
+    wav_path: PathBuf,
+    /// The file for `AudioBackend::Wav`, which is created for the first source and then written
+    /// to by every source after it, so seeking doesn't lose what was written before.
+    wav_file: Arc<Mutex<Option<WavFile>>>,
+    /// In percent, applied to the samples before they reach the sink.
+    volume: Arc<AtomicU32>,
+}
+
+impl AudioOutput {
+    pub(crate) fn new(backend: AudioBackend, wav_path: PathBuf) -> Self {
+        Self {
+            backend,
+            wav_path,
+            wav_file: Arc::new(Mutex::new(None)),
+            volume: Arc::new(AtomicU32::new(100)),
+        }
+    }
+
+    pub(crate) fn volume(&self) -> u32 {
+        self.volume.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn change_volume(&self, delta: i32) {
+        let volume = (self.volume() as i32 + delta).clamp(0, 200);
+        self.volume.store(volume as u32, Ordering::Relaxed);
+    }
+
+    /// Opens the sink. If the sound server can't be reached, the audio is dropped instead, so
+    /// that the video still plays in time.
+    fn open_sink(&self) -> Box<dyn AudioSink> {
+        let sink: Result<Box<dyn AudioSink>, Box<dyn Error>> = match self.backend {
+            AudioBackend::Pulse => CommandSink::new(Command::new("pacat").args([
+                "--playback",
+                "--raw",
+                "--format=s16le",
+                &format!("--channels={}", CHANNELS),
+                &format!("--rate={}", SAMPLE_RATE),
+                &format!("--latency-msec={}", SINK_LATENCY.as_millis()),
+                "--client-name=unicode_player",
+            ]))
+            .map(|sink| Box::new(sink) as _),
+            AudioBackend::Alsa => CommandSink::new(Command::new("aplay").args([
+                "-q",
+                "-t", "raw",
+                "-f", "S16_LE",
+                "-c", &CHANNELS.to_string(),
+                "-r", &SAMPLE_RATE.to_string(),
+                "-B", &SINK_LATENCY.as_micros().to_string(),
+                "-",
+            ]))
+            .map(|sink| Box::new(sink) as _),
+            AudioBackend::Null => Ok(Box::new(NullSink::new())),
+            AudioBackend::Wav => WavSink::new(&self.wav_path, &self.wav_file).map(|sink| Box::new(sink) as _),
+        };
+        sink.unwrap_or_else(|_| Box::new(NullSink::new()))
+    }
+
+    /// Starts playing the PCM in `stream` on a new thread, with a clock that starts at `start`.
+    pub(crate) fn play(&self, stream: impl Read + Send + 'static, start: Duration) -> Audio {
+        let clock = Arc::new(Clock::new(start));
+        let thread = thread::Builder::new()
+            .name("audio".to_string())
+            .spawn({
+                let sink = self.open_sink();
+                let clock = clock.clone();
+                let volume = self.volume.clone();
+                move || play_thread(stream, sink, &clock, &volume)
+            })
+            .unwrap();
+
+        Audio { clock, thread: Some(thread) }
+    }
+}
+
+/// Audio that's being played. The thread stops once its stream ends, which happens when the
+/// decoder is killed.
+pub(crate) struct Audio {
+    clock: Arc<Clock>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Audio {
+    pub(crate) fn clock(&self) -> Arc<Clock> {
+        self.clock.clone()
+    }
+
+    /// Stops the audio thread and waits for it to finish, so that its sink is closed before
+    /// another one is opened. The stream should be cut off first, or this waits for it to end.
+    pub(crate) fn join(&mut self) {
+        self.clock.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Audio {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// The position in the source based on how much audio has been played. Video frames are shown
+/// when this clock reaches them.
+pub(crate) struct Clock {
+    start: Duration,
+    state: Mutex<ClockState>,
+    /// Wakes the audio thread up when playback resumes or stops.
+    resumed: Condvar,
+}
+
+struct ClockState {
+    frames_played: u64,
+    latency: Duration,
+    /// Once the audio runs out, the clock runs on wall time. This keeps video going for sources
+    /// without sound. `wall_time` is how long it's run that way up to `wall_since`, which is `None`
+    /// while it's paused or the audio is still playing.
+    ended: bool,
+    wall_time: Duration,
+    wall_since: Option<Instant>,
+    paused: bool,
+    stopped: bool,
+}
+
+impl Clock {
+    fn new(start: Duration) -> Self {
+        Self {
+            start,
+            state: Mutex::new(ClockState {
+                frames_played: 0,
+                latency: Duration::ZERO,
+                ended: false,
+                wall_time: Duration::ZERO,
+                wall_since: None,
+                paused: false,
+                stopped: false,
+            }),
+            resumed: Condvar::new(),
+        }
+    }
+
+    /// A clock that only runs on wall time, for sources that play without audio.
+    pub(crate) fn without_audio(start: Duration) -> Self {
+        let clock = Self::new(start);
+        clock.end();
+        clock
+    }
+
+    pub(crate) fn now(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        let played = Duration::from_secs_f64(state.frames_played as f64 / SAMPLE_RATE as f64);
+        let wall_time = state.wall_time + state.wall_since.map_or(Duration::ZERO, |since| since.elapsed());
+        // Whatever is still buffered in the sink after the audio ends plays out on wall time too
+        self.start + (played + wall_time).saturating_sub(state.latency)
+    }
+
+    /// Holds the clock where it is. While it's paused, the audio thread stops feeding the sink
+    /// and stops reading from the decoder, which then blocks on the full pipe.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused == paused {
+            return;
+        }
+        state.paused = paused;
+        if state.ended {
+            if paused {
+                let since = state.wall_since.take();
+                state.wall_time += since.map_or(Duration::ZERO, |since| since.elapsed());
+            } else {
+                state.wall_since = Some(Instant::now());
+            }
+        }
+        self.resumed.notify_all();
+    }
+
+    fn advance(&self, frames: usize, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.frames_played += frames as u64;
+        state.latency = latency;
+    }
+
+    fn end(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.ended = true;
+        if !state.paused {
+            state.wall_since = Some(Instant::now());
+        }
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.resumed.notify_all();
+    }
+
+    /// Blocks while the clock is paused, and returns whether the audio should keep playing.
+    fn wait_for_playback(&self) -> bool {
+        let state = self
+            .resumed
+            .wait_while(self.state.lock().unwrap(), |state| state.paused && !state.stopped)
+            .unwrap();
+        !state.stopped
+    }
+}
+
+fn play_thread(mut stream: impl Read, mut sink: Box<dyn AudioSink>, clock: &Clock, volume: &AtomicU32) {
+    let mut chunk = vec![0; CHUNK_FRAMES * BYTES_PER_FRAME];
+    while clock.wait_for_playback() {
+        let len = read_chunk(&mut stream, &mut chunk);
+        // Only whole frames are played, anything after that is the stream being cut off
+        let len = len - len % BYTES_PER_FRAME;
+        if len == 0 {
+            break;
+        }
+
+        let samples = &mut chunk[..len];
+        apply_volume(samples, volume.load(Ordering::Relaxed));
+        if sink.write(samples).is_err() {
+            break;
+        }
+        clock.advance(len / BYTES_PER_FRAME, sink.latency());
+    }
+    clock.end();
+}
+
+/// Fills as much of `buf` as the stream has left.
+fn read_chunk(stream: &mut impl Read, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    while len < buf.len() {
+        match stream.read(&mut buf[len..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => len += n,
+        }
+    }
+    len
+}
+
+fn apply_volume(samples: &mut [u8], volume: u32) {
+    if volume == 100 {
+        return;
+    }
+    for sample in samples.chunks_exact_mut(2) {
+        let value = i16::from_le_bytes([sample[0], sample[1]]) as i32 * volume as i32 / 100;
+        let value = value.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        sample.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Somewhere to play decoded audio.
+trait AudioSink: Send {
+    /// Plays interleaved samples in the format given by `SAMPLE_RATE` and `CHANNELS`, blocking
+    /// until the sink has room for them.
+    fn write(&mut self, samples: &[u8]) -> io::Result<()>;
+
+    /// How far behind the samples that were written the speakers are.
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Pipes the samples to a player program, which blocks once its buffer is full.
+struct CommandSink {
+    process: Child,
+    stdin: ChildStdin,
+}
+
+impl CommandSink {
+    fn new(command: &mut Command) -> Result<Self, Box<dyn Error>> {
+        let mut process = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = process.stdin.take().ok_or("Couldn't get audio player stdin")?;
+        Ok(Self { process, stdin })
+    }
+}
+
+impl AudioSink for CommandSink {
+    fn write(&mut self, samples: &[u8]) -> io::Result<()> {
+        self.stdin.write_all(samples)
+    }
+
+    fn latency(&self) -> Duration {
+        SINK_LATENCY
+    }
+}
+
+impl Drop for CommandSink {
+    fn drop(&mut self) {
+        // Stop right away instead of playing out the buffer
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Takes samples no faster than they would be played.
+struct NullSink {
+    /// When the samples written so far would be done playing.
+    done_at: Option<Instant>,
+}
+
+impl NullSink {
+    fn new() -> Self {
+        Self { done_at: None }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn write(&mut self, samples: &[u8]) -> io::Result<()> {
+        let now = Instant::now();
+        // After a pause there's nothing left playing, like a real sink that ran dry
+        let start = self.done_at.map_or(now, |done_at| done_at.max(now));
+        let length = Duration::from_secs_f64((samples.len() / BYTES_PER_FRAME) as f64 / SAMPLE_RATE as f64);
+        let done_at = start + length;
+        self.done_at = Some(done_at);
+        thread::sleep(done_at - now);
+        Ok(())
+    }
+}
+
+/// A WAV file that samples are added to the end of.
+struct WavFile {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavFile {
+    fn create(path: &PathBuf) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // The lengths are filled in whenever a sink is done with the file
+        write_wav_header(&mut file, 0)?;
+        Ok(Self { file, data_len: 0 })
+    }
+
+    fn write(&mut self, samples: &[u8]) -> io::Result<()> {
+        self.file.write_all(samples)?;
+        self.data_len = self.data_len.saturating_add(samples.len() as u32);
+        Ok(())
+    }
+
+    /// Writes the lengths so far into the header, and goes back to the end for more samples.
+    fn finish_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.file, self.data_len)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+/// Writes the samples to the end of a WAV file, at the speed they would be played so that the
+/// video runs as normal.
+struct WavSink {
+    file: Arc<Mutex<Option<WavFile>>>,
+    pacing: NullSink,
+}
+
+impl WavSink {
+    /// Creates the file at `path` if `file` doesn't have it open yet.
+    fn new(path: &PathBuf, file: &Arc<Mutex<Option<WavFile>>>) -> Result<Self, Box<dyn Error>> {
+        let mut opened = file.lock().unwrap();
+        if opened.is_none() {
+            *opened = Some(WavFile::create(path)?);
+        }
+        Ok(Self { file: file.clone(), pacing: NullSink::new() })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, samples: &[u8]) -> io::Result<()> {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            file.write(samples)?;
+        }
+        self.pacing.write(samples)
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let _ = file.finish_header();
+        }
+    }
+}
+
+fn write_wav_header(out: &mut impl Write, data_len: u32) -> io::Result<()> {
+    let block_align = CHANNELS * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&data_len.saturating_add(36).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // Uncompressed PCM
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    out.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Plays `frames` frames of silence and waits for them to be written.
+    fn play_silence(output: &AudioOutput, frames: usize) {
+        let mut audio = output.play(Cursor::new(vec![0; frames * BYTES_PER_FRAME]), Duration::ZERO);
+        while !audio.thread.as_ref().unwrap().is_finished() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        audio.join();
+    }
+
+    #[test]
+    fn wav_keeps_every_source() {
+        let path = std::env::temp_dir().join(format!("unicode_player_append_{}.wav", std::process::id()));
+        let output = AudioOutput::new(AudioBackend::Wav, path.clone());
+        // Like a seek, which opens a new sink for the new source
+        play_silence(&output, CHUNK_FRAMES * 2);
+        play_silence(&output.clone(), CHUNK_FRAMES * 3);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        let data_len = CHUNK_FRAMES * 5 * BYTES_PER_FRAME;
+        assert_eq!(bytes.len(), 44 + data_len);
+        assert_eq!(bytes[4..8], (data_len as u32 + 36).to_le_bytes());
+        assert_eq!(bytes[40..44], (data_len as u32).to_le_bytes());
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs;
+use std::io;
+
+use crate::export::Exporter;
+use crate::recording::Header;
+use crate::screen::{Frame, Screen};
+
+/// The script that plays the frames, with `{{...}}` in place of the settings and the data.
+const TEMPLATE: &str = include_str!("bbs_script.js");
+
+/// How often a keyframe comes when the script has to fall back to delta frames.
+const DELTA_KEYFRAME_SECONDS: u32 = 10;
+
+/// Writes a script for the BBS that plays the video, filled in from `bbs_script.js`. The frames
+/// are all kept until the end, since whether they fit in `max_size` decides how they're stored.
+pub(crate) struct BbsScriptWriter {
+    path: String,
+    header: Header,
+    max_size: usize,
+    frames: Vec<Frame>,
+}
+
+impl BbsScriptWriter {
+    pub(crate) fn create(path: &str, header: &Header, max_size: usize) -> io::Result<Self> {
+        // Fail now rather than after the whole video has been rendered
+        fs::write(path, "")?;
+        Ok(Self {
+            path: path.to_string(),
+            header: header.clone(),
+            max_size,
+            frames: Vec::new(),
+        })
+    }
+
+    /// Fills in the template with every `step`th frame.
+    fn script(&self, step: usize, keyframe_interval: usize) -> String {
+        let mut screen = Screen::repeating();
+        let frames = self
+            .frames
+            .iter()
+            .step_by(step)
+            .enumerate()
+            .map(|(i, frame)| {
+                if i % keyframe_interval == 0 {
+                    screen.invalidate();
+                }
+                screen.draw(frame.clone())
+            })
+            .collect::<Vec<String>>();
+
+        TEMPLATE
+            .replace("{{WIDTH}}", &self.header.width.to_string())
+            .replace("{{HEIGHT}}", &self.header.height.to_string())
+            .replace("{{FRAMERATE}}", &(self.header.framerate as f64 / step as f64).to_string())
+            .replace("{{KEYFRAME_INTERVAL}}", &keyframe_interval.to_string())
+            // A JSON array of strings is also a JavaScript one. Frames can have newlines in them,
+            // like Sixel images do, so they can't be joined into one string
+            .replace("{{FRAMES}}", &serde_json::to_string(&frames).unwrap())
+    }
+}
+
+impl Exporter for BbsScriptWriter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Tries whole frames, then delta frames, then halves the framerate until the script fits.
+    fn finish(&mut self) -> io::Result<()> {
+        let mut step = 1;
+        loop {
+            let framerate = self.header.framerate as usize / step;
+            let delta_interval = (framerate * DELTA_KEYFRAME_SECONDS as usize).max(1);
+
+            for keyframe_interval in [1, delta_interval] {
+                let script = self.script(step, keyframe_interval);
+                if script.len() <= self.max_size {
+                    if step > 1 || keyframe_interval > 1 {
+                        eprintln!(
+                            "The script was over {} bytes, so it plays at {} fps with a keyframe every {} frames",
+                            self.max_size,
+                            self.header.framerate as f64 / step as f64,
+                            keyframe_interval,
+                        );
+                    }
+                    return fs::write(&self.path, script);
+                }
+            }
+
+            if framerate <= 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The script can't be made smaller than {} bytes", self.max_size),
+                ));
+            }
+            step *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::{text_cells, Cell, Graphic};
+
+    fn writer(max_size: usize) -> BbsScriptWriter {
+        let header = Header {
+            framerate: 10,
+            width: 10,
+            height: 4,
+            renderer: None,
+            color_depth: None,
+            char_height: None,
+            title: None,
+            duration: None,
+            keyframe_interval: None,
+        };
+        let rows = ["The quick ", "brown fox ", "jumps over", "a lazy dog"];
+        let frames = (0..20)
+            .map(|i| {
+                let mut frame = Frame { cells: rows.iter().map(|row| text_cells(row)).collect(), ..Frame::default() };
+                frame.cells[0][0] = Cell::plain(char::from(b'0' + i % 10));
+                frame
+            })
+            .collect();
+        BbsScriptWriter { path: String::new(), header, max_size, frames }
+    }
+
+    /// The value of the `const` called `name` in the script.
+    fn constant<'a>(script: &'a str, name: &str) -> &'a str {
+        let prefix = format!("const {} = ", name);
+        let line = script.lines().find(|line| line.starts_with(&prefix)).unwrap();
+        line[prefix.len()..].trim_end_matches(';')
+    }
+
+    /// Finishes the script and reads back its framerate, keyframe interval and frames.
+    fn finish(mut writer: BbsScriptWriter, name: &str) -> io::Result<(String, String, Vec<String>)> {
+        let path = std::env::temp_dir().join(format!("unicode_player_{}_{}.js", name, std::process::id()));
+        writer.path = path.to_str().unwrap().to_string();
+        let result = writer.finish();
+        let script = fs::read_to_string(&path).unwrap_or_default();
+        let _ = fs::remove_file(path);
+        result?;
+
+        let frames = serde_json::from_str(constant(&script, "data")).unwrap();
+        Ok((constant(&script, "FRAMERATE").to_string(), constant(&script, "KEYFRAME_INTERVAL").to_string(), frames))
+    }
+
+    #[test]
+    fn frames_with_newlines() {
+        let mut writer = writer(usize::MAX);
+        writer.frames[1].graphics.push(Graphic { x: 2, y: 1, data: "\x1bPq#0;2;0;0;0\n#0~~\n-\x1b\\".to_string() });
+        let script = writer.script(1, 1);
+        assert_eq!(constant(&script, "WIDTH"), "10");
+        assert_eq!(constant(&script, "HEIGHT"), "4");
+        assert!(!script.contains("{{"));
+
+        let (framerate, keyframe_interval, frames) = finish(writer, "newlines").unwrap();
+        assert_eq!((framerate.as_str(), keyframe_interval.as_str()), ("10", "1"));
+        assert_eq!(frames.len(), 20);
+        assert!(frames[1].contains("#0~~\n-"));
+        assert!(frames[1].contains("brown fox "));
+    }
+
+    #[test]
+    fn size_fallbacks() {
+        let whole = writer(0).script(1, 1).len();
+        let delta = writer(0).script(1, 100).len();
+        let halved = writer(0).script(2, 50).len();
+        assert!(halved < delta && delta < whole);
+
+        let (framerate, keyframe_interval, frames) = finish(writer(whole), "whole").unwrap();
+        assert_eq!((framerate.as_str(), keyframe_interval.as_str(), frames.len()), ("10", "1", 20));
+        // Every frame redraws the whole screen
+        assert!(frames.iter().all(|frame| frame.contains("a lazy dog")));
+
+        let (framerate, keyframe_interval, frames) = finish(writer(whole - 1), "delta").unwrap();
+        assert_eq!((framerate.as_str(), keyframe_interval.as_str(), frames.len()), ("10", "100", 20));
+        // Only the first frame is a keyframe
+        assert!(frames[0].contains("a lazy dog"));
+        assert!(frames[1..].iter().all(|frame| !frame.contains("a lazy dog")));
+
+        let (framerate, keyframe_interval, frames) = finish(writer(delta - 1), "halved").unwrap();
+        assert_eq!((framerate.as_str(), keyframe_interval.as_str(), frames.len()), ("5", "50", 10));
+
+        let error = finish(writer(100), "too_small").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
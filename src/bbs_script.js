@@ -0,0 +1,123 @@
+// Generated by unicode_player with `--format bbs-script`
+
+const WIDTH = {{WIDTH}};
+const HEIGHT = {{HEIGHT}};
+const FRAMERATE = {{FRAMERATE}};
+const KEYFRAME_INTERVAL = {{KEYFRAME_INTERVAL}};
+const COLOR = 17;
+
+function getName() {
+    return "unicode_player";
+}
+
+let frames;
+let firstFrameTime;
+
+// What's on the screen, so that only rows that changed are drawn again
+let drawn = [];
+
+function onConnect() {
+    frames = data;
+    firstFrameTime = Date.now();
+    drawn = [];
+    clearScreen();
+}
+
+function onUpdate() {
+    let idx = Math.floor((Date.now() - firstFrameTime) / 1000 * FRAMERATE);
+    displayFrame(Math.min(idx, frames.length - 1));
+}
+
+function onInput() {
+
+}
+
+// The frame being rebuilt, as rows of characters, and which frame it's up to
+let screen = [];
+let screenIndex = -1;
+
+// Frames are stored the way unicode_player draws them to a terminal: cursor moves, text, and REP
+// for runs of the same character. Every KEYFRAME_INTERVAL frames one starts from a blank screen,
+// and the ones in between only draw what changed.
+function rebuildFrame(idx) {
+    let keyframe = idx - idx % KEYFRAME_INTERVAL;
+    if (screenIndex > idx || screenIndex < keyframe) {
+        screen = [];
+        for (let y = 0; y < HEIGHT; y++) {
+            screen.push(new Array(WIDTH).fill(" "));
+        }
+        screenIndex = keyframe - 1;
+    }
+    while (screenIndex < idx) {
+        screenIndex++;
+        applyFrame(frames[screenIndex]);
+    }
+    return screen.map(row => row.join(""));
+}
+
+function applyFrame(text) {
+    // Indexing by code point keeps the block characters whole
+    let chars = Array.from(text);
+    let x = 0;
+    let y = 0;
+    let last = " ";
+
+    let put = c => {
+        if (y < screen.length) {
+            screen[y][x] = c;
+        }
+        x++;
+        if (x >= WIDTH) {
+            x = 0;
+            y++;
+        }
+    };
+
+    let i = 0;
+    while (i < chars.length) {
+        if (chars[i] !== "\x1b") {
+            last = chars[i++];
+            put(last);
+            continue;
+        }
+
+        if (chars[i + 1] === "[") {
+            let end = i + 2;
+            while (end < chars.length && !(chars[end] >= "\x40" && chars[end] <= "\x7e")) {
+                end++;
+            }
+            let params = chars.slice(i + 2, end).join("").split(";").map(p => parseInt(p) || 0);
+            if (chars[end] === "H") {
+                y = Math.max(params[0], 1) - 1;
+                x = Math.max(params.length > 1 ? params[1] : 1, 1) - 1;
+            } else if (chars[end] === "b") {
+                for (let n = 0; n < Math.max(params[0], 1); n++) {
+                    put(last);
+                }
+            } else if (chars[end] === "K" && y < screen.length) {
+                screen[y].fill(" ", x);
+            }
+            // Colors are left out, since this can only draw in one
+            i = end + 1;
+        } else {
+            // Anything else runs until the string terminator
+            i += 2;
+            while (i < chars.length && chars[i] !== "\x07" && !(chars[i] === "\x1b" && chars[i + 1] === "\\")) {
+                i++;
+            }
+            i += chars[i] === "\x07" ? 1 : 2;
+        }
+    }
+}
+
+function displayFrame(idx) {
+    let rows = rebuildFrame(idx);
+    for (let y = 0; y < rows.length; y++) {
+        if (rows[y] !== drawn[y]) {
+            drawText(rows[y], COLOR, 0, y);
+            drawn[y] = rows[y];
+        }
+    }
+}
+
+const data = {{FRAMES}};
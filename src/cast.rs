@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::export::Exporter;
+use crate::recording::Header;
+use crate::screen::{Frame, Screen};
+
+/// The first line of an asciicast v2 file.
+#[derive(Serialize)]
+struct CastHeader<'a> {
+    version: u32,
+    width: u32,
+    height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    env: CastEnv,
+}
+
+#[derive(Serialize)]
+struct CastEnv {
+    #[serde(rename = "TERM")]
+    term: &'static str,
+}
+
+/// Writes an asciicast v2 file, where each frame is an output event holding only what changed
+/// since the frame before.
+pub(crate) struct CastWriter {
+    file: BufWriter<File>,
+    screen: Screen,
+    framerate: u32,
+    frames: u64,
+}
+
+impl CastWriter {
+    pub(crate) fn create(path: &str, header: &Header) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let cast_header = CastHeader {
+            version: 2,
+            width: header.width,
+            height: header.height,
+            title: header.title.as_deref(),
+            env: CastEnv { term: "xterm-256color" },
+        };
+        serde_json::to_writer(&mut file, &cast_header)?;
+        writeln!(file)?;
+
+        // The cursor would otherwise sit wherever the last changed cell was
+        write_event(&mut file, 0.0, "\x1b[?25l\x1b[2J")?;
+
+        Ok(Self {
+            file,
+            screen: Screen::new(),
+            framerate: header.framerate,
+            frames: 0,
+        })
+    }
+}
+
+impl Exporter for CastWriter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        let time = self.frames as f64 / self.framerate as f64;
+        self.frames += 1;
+
+        let output = self.screen.draw(frame);
+        if output.is_empty() {
+            return Ok(());
+        }
+        write_event(&mut self.file, time, &output)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Holds the last frame up for as long as the others were
+        let time = self.frames as f64 / self.framerate as f64;
+        write_event(&mut self.file, time, "\x1b[?25h")?;
+        self.file.flush()
+    }
+}
+
+fn write_event(file: &mut impl Write, time: f64, data: &str) -> io::Result<()> {
+    writeln!(file, "[{:.6}, \"o\", {}]", time, serde_json::to_string(data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::palette::TermColor;
+    use crate::screen::{text_cells, Cell};
+
+    #[test]
+    fn cast() {
+        let path = std::env::temp_dir().join(format!("unicode_player_{}.cast", std::process::id()));
+        let header = Header {
+            framerate: 4,
+            width: 2,
+            height: 2,
+            renderer: None,
+            color_depth: None,
+            char_height: None,
+            title: Some("Test \"clip\"".to_string()),
+            duration: None,
+            keyframe_interval: None,
+        };
+        let frame = Frame { cells: vec![text_cells("ab"), text_cells("cd")], ..Frame::default() };
+        let mut changed = frame.clone();
+        changed.cells[1][1] = Cell { fg: Some(TermColor::Rgb([255, 0, 0])), ..Cell::plain('X') }.bold();
+
+        let mut writer = CastWriter::create(path.to_str().unwrap(), &header).unwrap();
+        writer.write_frame(frame.clone()).unwrap();
+        // Nothing changes in the second frame, so it doesn't get an event
+        writer.write_frame(frame).unwrap();
+        writer.write_frame(changed).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let cast = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            cast.lines().collect::<Vec<_>>(),
+            [
+                r#"{"version":2,"width":2,"height":2,"title":"Test \"clip\"","env":{"TERM":"xterm-256color"}}"#,
+                r#"[0.000000, "o", "\u001b[?25l\u001b[2J"]"#,
+                r#"[0.000000, "o", "\u001b[1;1Hab\u001b[2;1Hcd"]"#,
+                r#"[0.500000, "o", "\u001b[2;2H\u001b[1;38;2;255;0;0mX\u001b[0m"]"#,
+                r#"[0.750000, "o", "\u001b[?25h"]"#,
+            ]
+        );
+    }
+}
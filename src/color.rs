@@ -0,0 +1,91 @@
+use image::Rgb;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref SRGB_TO_LINEAR: [f32; 256] = {
+        let mut table = [0.0; 256];
+        for (i, v) in table.iter_mut().enumerate() {
+            let c = i as f32 / 255.0;
+            *v = if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            };
+        }
+        table
+    };
+}
+
+fn linear_to_srgb(c: f64) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+/// A color in Björn Ottosson's OKLab space, where euclidean distance roughly matches how
+/// different two colors look.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) struct Oklab {
+    pub(crate) l: f32,
+    pub(crate) a: f32,
+    pub(crate) b: f32,
+}
+
+impl Oklab {
+    pub(crate) fn from_rgb(color: &Rgb<u8>) -> Self {
+        // The matrices are given to more precision than f32 holds, so the math is done in f64
+        let r = SRGB_TO_LINEAR[color[0] as usize] as f64;
+        let g = SRGB_TO_LINEAR[color[1] as usize] as f64;
+        let b = SRGB_TO_LINEAR[color[2] as usize] as f64;
+
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+        Self {
+            l: (0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s) as f32,
+            a: (1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s) as f32,
+            b: (0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s) as f32,
+        }
+    }
+
+    pub(crate) fn to_rgb(self) -> Rgb<u8> {
+        let (lightness, a, b) = (self.l as f64, self.a as f64, self.b as f64);
+        let l = lightness + 0.3963377774 * a + 0.2158037573 * b;
+        let m = lightness - 0.1055613458 * a - 0.0638541728 * b;
+        let s = lightness - 0.0894841775 * a - 1.2914855480 * b;
+
+        let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+
+        Rgb([
+            linear_to_srgb(4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s),
+            linear_to_srgb(-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s),
+            linear_to_srgb(-0.0041960863 * l - 0.7034186168 * m + 1.7076147010 * s),
+        ])
+    }
+
+    pub(crate) fn distance_squared(&self, other: &Self) -> f32 {
+        (self.l - other.l).powi(2) + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)
+    }
+}
+
+/// The channel values of the 6x6x6 color cube in the xterm 256-color palette.
+pub(crate) const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+pub(crate) fn nearest_cube_level(c: u8) -> usize {
+    CUBE_LEVELS
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, level)| (**level as i32 - c as i32).abs())
+        .unwrap()
+        .0
+}
+
+/// Snaps each channel to the closest value in the xterm color cube.
+pub(crate) fn quantize_to_cube(color: Rgb<u8>) -> Rgb<u8> {
+    Rgb(color.0.map(|c| CUBE_LEVELS[nearest_cube_level(c)]))
+}
@@ -0,0 +1,106 @@
+use clap::ValueEnum;
+use image::{Rgb, RgbImage};
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Bayer4,
+    Bayer8,
+}
+
+/// (dx, dy, weight) for each neighbour that receives part of a pixel's quantization error.
+const FLOYD_STEINBERG: &[(i32, i32, f32)] = &[
+    (1, 0, 7.0 / 16.0),
+    (-1, 1, 3.0 / 16.0),
+    (0, 1, 5.0 / 16.0),
+    (1, 1, 1.0 / 16.0),
+];
+
+// Atkinson only passes on 3/4 of the error, which keeps highlights and shadows clean
+const ATKINSON: &[(i32, i32, f32)] = &[
+    (1, 0, 1.0 / 8.0),
+    (2, 0, 1.0 / 8.0),
+    (-1, 1, 1.0 / 8.0),
+    (0, 1, 1.0 / 8.0),
+    (1, 1, 1.0 / 8.0),
+    (0, 2, 1.0 / 8.0),
+];
+
+const BAYER4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+const BAYER8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+impl Dither {
+    /// Dithers `img` in place so that every pixel ends up as a color returned by `quantize`.
+    /// `spread` is roughly the distance between neighbouring output levels, and sets how strong
+    /// ordered dithering is.
+    pub(crate) fn apply(&self, img: &mut RgbImage, spread: f32, quantize: impl Fn(Rgb<u8>) -> Rgb<u8>) {
+        match self {
+            Self::None => {}
+            Self::FloydSteinberg => diffuse_error(img, FLOYD_STEINBERG, quantize),
+            Self::Atkinson => diffuse_error(img, ATKINSON, quantize),
+            // The threshold only depends on the position in the image, so still areas stay the
+            // same from one frame to the next
+            Self::Bayer4 => ordered(img, spread, |x, y| (BAYER4[y % 4][x % 4] as f32 + 0.5) / 16.0, quantize),
+            Self::Bayer8 => ordered(img, spread, |x, y| (BAYER8[y % 8][x % 8] as f32 + 0.5) / 64.0, quantize),
+        }
+    }
+}
+
+fn diffuse_error(img: &mut RgbImage, kernel: &[(i32, i32, f32)], quantize: impl Fn(Rgb<u8>) -> Rgb<u8>) {
+    let (width, height) = (img.width() as i32, img.height() as i32);
+    let mut errors = vec![[0.0f32; 3]; (width * height) as usize];
+
+    for y in 0..height {
+        for x in 0..width {
+            let px = img.get_pixel_mut(x as u32, y as u32);
+            let error = errors[(y * width + x) as usize];
+            let wanted = [
+                px[0] as f32 + error[0],
+                px[1] as f32 + error[1],
+                px[2] as f32 + error[2],
+            ];
+            let quantized = quantize(Rgb(wanted.map(|c| c.round().clamp(0.0, 255.0) as u8)));
+            *px = quantized;
+
+            for &(dx, dy, weight) in kernel {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || nx >= width || ny >= height {
+                    continue;
+                }
+                let neighbour = &mut errors[(ny * width + nx) as usize];
+                for c in 0..3 {
+                    neighbour[c] += (wanted[c] - quantized[c] as f32) * weight;
+                }
+            }
+        }
+    }
+}
+
+fn ordered(
+    img: &mut RgbImage,
+    spread: f32,
+    threshold: impl Fn(usize, usize) -> f32,
+    quantize: impl Fn(Rgb<u8>) -> Rgb<u8>,
+) {
+    for (x, y, px) in img.enumerate_pixels_mut() {
+        let offset = (threshold(x as usize, y as usize) - 0.5) * spread;
+        *px = quantize(Rgb(px.0.map(|c| (c as f32 + offset).round().clamp(0.0, 255.0) as u8)));
+    }
+}
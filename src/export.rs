@@ -0,0 +1,63 @@
+use std::io;
+
+use clap::ValueEnum;
+
+use crate::ans::{AnsFramesWriter, AnsWriter};
+use crate::bbs::BbsScriptWriter;
+use crate::cast::CastWriter;
+use crate::html::HtmlWriter;
+use crate::recording::{Header, RecordingWriter};
+use crate::screen::Frame;
+use crate::svg::SvgFramesWriter;
+
+/// What `--output` writes.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// A recording that can be played back with `play-recording`
+    Recording,
+    /// An asciicast v2 file, for asciinema
+    Cast,
+    /// A web page that plays the frames, with the colors done in CSS
+    Html,
+    /// A directory with an SVG file for each frame
+    SvgFrames,
+    /// ANSI art in code page 437 and 16 colors, as one ANSImation with a SAUCE record
+    Ans,
+    /// A directory with an ANSI art file for each frame
+    AnsFrames,
+    /// A script for the BBS that plays the video
+    BbsScript,
+}
+
+/// Settings that only some of the formats use.
+pub(crate) struct ExportOptions {
+    /// Whether recordings get an index of their frames.
+    pub(crate) index: bool,
+    /// Who to credit in the SAUCE record of ANSI art.
+    pub(crate) author: String,
+    /// The most a BBS script can take up, in bytes.
+    pub(crate) max_script_size: usize,
+}
+
+impl ExportFormat {
+    /// Opens `path` for writing in this format.
+    pub(crate) fn create(&self, path: &str, header: &Header, options: &ExportOptions) -> io::Result<Box<dyn Exporter>> {
+        Ok(match self {
+            Self::Recording => Box::new(RecordingWriter::create(path, header, options.index)?),
+            Self::Cast => Box::new(CastWriter::create(path, header)?),
+            Self::Html => Box::new(HtmlWriter::create(path, header)?),
+            Self::SvgFrames => Box::new(SvgFramesWriter::create(path, header)?),
+            Self::Ans => Box::new(AnsWriter::create(path, header, &options.author)?),
+            Self::AnsFrames => Box::new(AnsFramesWriter::create(path, header, &options.author)?),
+            Self::BbsScript => Box::new(BbsScriptWriter::create(path, header, options.max_script_size)?),
+        })
+    }
+}
+
+/// Writes rendered frames to a file, one at a time in order.
+pub(crate) trait Exporter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()>;
+
+    /// Writes anything that has to come after the last frame, and flushes the file.
+    fn finish(&mut self) -> io::Result<()>;
+}
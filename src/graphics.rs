@@ -0,0 +1,190 @@
+use std::fmt::Write;
+
+use base64::Engine;
+use image::RgbImage;
+
+use crate::color::{nearest_cube_level, CUBE_LEVELS};
+
+/// The kitty image ID used for the player. Sending a new image with the same ID replaces the old
+/// one, so the terminal only ever holds one frame.
+pub(crate) const KITTY_IMAGE_ID: u32 = 0x5550;
+
+/// Kitty's limit on the size of each chunk of base64 data.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Encodes `img` as a kitty graphics protocol transmit-and-display command, stretched over
+/// `cols` by `rows` cells starting at the cursor.
+pub(crate) fn encode_kitty(img: &RgbImage, cols: u32, rows: u32) -> String {
+    let data = base64::engine::general_purpose::STANDARD.encode(img.as_raw());
+    let chunks = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect::<Vec<_>>();
+
+    let mut out = String::with_capacity(data.len() + chunks.len() * 16 + 64);
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        if i == 0 {
+            write!(
+                out,
+                "\x1b_Ga=T,f=24,s={},v={},c={},r={},i={},p=1,C=1,q=2,m={};",
+                img.width(),
+                img.height(),
+                cols,
+                rows,
+                KITTY_IMAGE_ID,
+                more
+            )
+            .unwrap();
+        } else {
+            write!(out, "\x1b_Gm={};", more).unwrap();
+        }
+        // Base64 is always ASCII
+        out.push_str(std::str::from_utf8(chunk).unwrap());
+        out.push_str("\x1b\\");
+    }
+    out
+}
+
+/// Removes the player's image from a kitty terminal.
+pub(crate) fn delete_kitty() -> String {
+    format!("\x1b_Ga=d,d=I,i={},q=2\x1b\\", KITTY_IMAGE_ID)
+}
+
+fn cube_index(px: &image::Rgb<u8>) -> usize {
+    nearest_cube_level(px[0]) * 36 + nearest_cube_level(px[1]) * 6 + nearest_cube_level(px[2])
+}
+
+/// Encodes `img` as a sixel image using the 216 colors of the xterm color cube as registers.
+pub(crate) fn encode_sixel(img: &RgbImage) -> String {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let indices = img.pixels().map(cube_index).collect::<Vec<usize>>();
+
+    let mut out = String::new();
+    write!(out, "\x1bP0;1;0q\"1;1;{};{}", width, height).unwrap();
+
+    let mut used = [false; 216];
+    for i in &indices {
+        used[*i] = true;
+    }
+    for (i, _) in used.iter().enumerate().filter(|(_, used)| **used) {
+        let percent = |level: u8| level as u32 * 100 / 255;
+        write!(
+            out,
+            "#{};2;{};{};{}",
+            i,
+            percent(CUBE_LEVELS[i / 36]),
+            percent(CUBE_LEVELS[i / 6 % 6]),
+            percent(CUBE_LEVELS[i % 6])
+        )
+        .unwrap();
+    }
+
+    let mut band = vec![0u8; width];
+    for band_top in (0..height).step_by(6) {
+        let band_height = (height - band_top).min(6);
+
+        let mut colors = indices[band_top * width..(band_top + band_height) * width].to_vec();
+        colors.sort_unstable();
+        colors.dedup();
+
+        for (n, color) in colors.iter().enumerate() {
+            for (x, sixel) in band.iter_mut().enumerate() {
+                *sixel = (0..band_height)
+                    .filter(|dy| indices[(band_top + dy) * width + x] == *color)
+                    .map(|dy| 1 << dy)
+                    .sum();
+            }
+
+            if n > 0 {
+                out.push('$');
+            }
+            write!(out, "#{}", color).unwrap();
+            write_sixel_runs(&band, &mut out);
+        }
+        out.push('-');
+    }
+
+    out.push_str("\x1b\\");
+    out
+}
+
+/// Writes a row of sixels, using the repeat introducer for long runs.
+fn write_sixel_runs(band: &[u8], out: &mut String) {
+    let mut x = 0;
+    while x < band.len() {
+        let run = band[x..].iter().take_while(|s| **s == band[x]).count();
+        let c = (b'?' + band[x]) as char;
+        if run > 3 {
+            write!(out, "!{}{}", run, c).unwrap();
+        } else {
+            for _ in 0..run {
+                out.push(c);
+            }
+        }
+        x += run;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn sixel_colors() {
+        let img = RgbImage::from_fn(2, 2, |x, y| if x == y { Rgb([255, 0, 0]) } else { Rgb([0, 0, 0]) });
+        assert_eq!(encode_sixel(&img), "\x1bP0;1;0q\"1;1;2;2#0;2;0;0;0#180;2;100;0;0#0A@$#180@A-\x1b\\");
+    }
+
+    #[test]
+    fn sixel_bands() {
+        // Gray snaps to 135 in the cube, and the last column is white. The second band is one
+        // pixel high
+        let img = RgbImage::from_fn(4, 7, |x, _| if x < 3 { Rgb([128, 128, 128]) } else { Rgb([255, 255, 255]) });
+        assert_eq!(
+            encode_sixel(&img),
+            "\x1bP0;1;0q\"1;1;4;7#86;2;52;52;52#215;2;100;100;100#86~~~?$#215???~-#86@@@?$#215???@-\x1b\\"
+        );
+    }
+
+    #[test]
+    fn sixel_runs() {
+        let img = RgbImage::from_pixel(5, 7, Rgb([128, 128, 128]));
+        assert_eq!(encode_sixel(&img), "\x1bP0;1;0q\"1;1;5;7#86;2;52;52;52#86!5~-#86!5@-\x1b\\");
+    }
+
+    #[test]
+    fn kitty() {
+        let img = RgbImage::from_pixel(1, 1, Rgb([1, 2, 3]));
+        assert_eq!(
+            encode_kitty(&img, 2, 1),
+            "\x1b_Ga=T,f=24,s=1,v=1,c=2,r=1,i=21840,p=1,C=1,q=2,m=0;AQID\x1b\\"
+        );
+    }
+
+    #[test]
+    fn kitty_chunks() {
+        // 3168 bytes of pixels take up 4224 characters of base64
+        let img = RgbImage::new(33, 32);
+        assert_eq!(
+            encode_kitty(&img, 10, 5),
+            format!(
+                "\x1b_Ga=T,f=24,s=33,v=32,c=10,r=5,i=21840,p=1,C=1,q=2,m=1;{}\x1b\\\x1b_Gm=0;{}\x1b\\",
+                "A".repeat(4096),
+                "A".repeat(128)
+            )
+        );
+    }
+
+    #[test]
+    fn kitty_reuses_image_id() {
+        // Each frame is sent under the same ID so it replaces the one before, and deleting that
+        // ID clears whichever is showing
+        let frames = [
+            encode_kitty(&RgbImage::from_pixel(1, 1, Rgb([0, 0, 0])), 1, 1),
+            encode_kitty(&RgbImage::from_pixel(2, 1, Rgb([255, 255, 255])), 2, 1),
+        ];
+        assert_eq!(frames[0], "\x1b_Ga=T,f=24,s=1,v=1,c=1,r=1,i=21840,p=1,C=1,q=2,m=0;AAAA\x1b\\");
+        assert_eq!(frames[1], "\x1b_Ga=T,f=24,s=2,v=1,c=2,r=1,i=21840,p=1,C=1,q=2,m=0;////////\x1b\\");
+        assert_eq!(delete_kitty(), "\x1b_Ga=d,d=I,i=21840,q=2\x1b\\");
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use image::Rgb;
+
+use crate::export::Exporter;
+use crate::palette::{xterm_color, TermColor, ANSI16};
+use crate::recording::Header;
+use crate::screen::{Cell, Frame, WIDE_TAIL};
+
+/// The colors a terminal would most likely use for cells without their own.
+pub(crate) const DEFAULT_FG: Rgb<u8> = Rgb(ANSI16[7]);
+pub(crate) const DEFAULT_BG: Rgb<u8> = Rgb(ANSI16[0]);
+
+/// Turns terminal colors into CSS ones, keeping track of the palette changes sent with each
+/// frame in the 256 and 16 color modes.
+pub(crate) struct CssPalette {
+    colors: Vec<Rgb<u8>>,
+}
+
+impl CssPalette {
+    pub(crate) fn new() -> Self {
+        Self { colors: (0..=255).map(xterm_color).collect() }
+    }
+
+    /// Picks up the OSC 4 sequences in a frame's prelude.
+    pub(crate) fn update(&mut self, prelude: &str) {
+        for definition in prelude.split("\x1b]4;").skip(1) {
+            let Some((index, color)) = definition.split_once(";rgb:") else {
+                continue;
+            };
+            let channels = color
+                .split(['/', '\x1b', '\x07'])
+                .take(3)
+                .map(|channel| u8::from_str_radix(channel, 16))
+                .collect::<Result<Vec<u8>, _>>();
+            if let (Ok(index), Ok([r, g, b])) = (index.parse::<u8>(), channels.as_deref()) {
+                self.colors[index as usize] = Rgb([*r, *g, *b]);
+            }
+        }
+    }
+
+    pub(crate) fn rgb(&self, color: TermColor) -> Rgb<u8> {
+        match color {
+            TermColor::Rgb(rgb) => Rgb(rgb),
+            TermColor::Indexed(index) => self.colors[index as usize],
+        }
+    }
+
+    /// The foreground and background a cell is drawn in, after reverse video.
+    pub(crate) fn cell_colors(&self, cell: &Cell) -> (Rgb<u8>, Rgb<u8>) {
+        let fg = cell.fg.map_or(DEFAULT_FG, |fg| self.rgb(fg));
+        let bg = cell.bg.map_or(DEFAULT_BG, |bg| self.rgb(bg));
+        if cell.reverse {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+}
+
+pub(crate) fn css_color(color: Rgb<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Escapes text to go inside HTML or SVG elements and attributes.
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Cells next to each other in a row that are all drawn the same way.
+pub(crate) struct StyleRun {
+    pub(crate) x: usize,
+    /// How many columns the run covers, which counts wide characters twice.
+    pub(crate) width: usize,
+    pub(crate) fg: Rgb<u8>,
+    pub(crate) bg: Rgb<u8>,
+    pub(crate) bold: bool,
+    pub(crate) text: String,
+}
+
+/// Splits a row into runs of cells with the same colors and boldness.
+pub(crate) fn style_runs(row: &[Cell], palette: &CssPalette) -> Vec<StyleRun> {
+    let mut runs: Vec<StyleRun> = Vec::new();
+    for (x, cell) in row.iter().enumerate() {
+        if cell.ch == WIDE_TAIL {
+            if let Some(run) = runs.last_mut() {
+                run.width += 1;
+            }
+            continue;
+        }
+        let (fg, bg) = palette.cell_colors(cell);
+        match runs.last_mut() {
+            Some(run) if (run.fg, run.bg, run.bold) == (fg, bg, cell.bold) => {
+                run.width += 1;
+                run.text.push(cell.ch);
+            }
+            _ => runs.push(StyleRun { x, width: 1, fg, bg, bold: cell.bold, text: cell.ch.to_string() }),
+        }
+    }
+    runs
+}
+
+/// A frame as the inside of a `<pre>`, with a span for each run of differently colored cells.
+fn frame_html(frame: &Frame, palette: &CssPalette) -> String {
+    let mut out = String::new();
+    for (y, row) in frame.cells.iter().enumerate() {
+        if y > 0 {
+            out.push('\n');
+        }
+        for run in style_runs(row, palette) {
+            let mut style = Vec::new();
+            if run.fg != DEFAULT_FG {
+                style.push(format!("color:{}", css_color(run.fg)));
+            }
+            if run.bg != DEFAULT_BG {
+                style.push(format!("background:{}", css_color(run.bg)));
+            }
+            if run.bold {
+                style.push("font-weight:bold".to_string());
+            }
+
+            if style.is_empty() {
+                out.push_str(&escape_xml(&run.text));
+            } else {
+                write!(out, "<span style=\"{}\">{}</span>", style.join(";"), escape_xml(&run.text)).unwrap();
+            }
+        }
+    }
+    out
+}
+
+/// Writes a single HTML page that plays the frames with a bit of JavaScript. Click the video to
+/// pause it.
+pub(crate) struct HtmlWriter {
+    file: BufWriter<File>,
+    palette: CssPalette,
+}
+
+impl HtmlWriter {
+    pub(crate) fn create(path: &str, header: &Header) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let title = escape_xml(header.title.as_deref().unwrap_or("unicode_player"));
+        write!(
+            file,
+            "<!DOCTYPE html>\n\
+            <html>\n\
+            <head>\n\
+            <meta charset=\"utf-8\">\n\
+            <title>{}</title>\n\
+            <style>\n\
+            body {{ background: #222; color: #ccc; font-family: sans-serif; }}\n\
+            #player {{ display: inline-block; margin: 0; font-size: 12px; line-height: 1; color: {}; background: {}; cursor: pointer; }}\n\
+            </style>\n\
+            </head>\n\
+            <body>\n\
+            <h1>{}</h1>\n\
+            <pre id=\"player\"></pre>\n\
+            <script>\n\
+            const framerate = {};\n\
+            const frames = [\n",
+            title,
+            css_color(DEFAULT_FG),
+            css_color(DEFAULT_BG),
+            title,
+            header.framerate,
+        )?;
+
+        Ok(Self { file, palette: CssPalette::new() })
+    }
+}
+
+impl Exporter for HtmlWriter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        self.palette.update(&frame.prelude);
+        // The markup never has a `<` of its own outside the tags, so it can't end the script
+        writeln!(self.file, "{},", serde_json::to_string(&frame_html(&frame, &self.palette))?)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        write!(self.file, "{}", PLAYER_SCRIPT)?;
+        self.file.flush()
+    }
+}
+
+const PLAYER_SCRIPT: &str = r#"];
+
+const player = document.getElementById("player");
+let start = performance.now();
+let pausedAt = null;
+let shown = -1;
+
+player.addEventListener("click", () => {
+    if (pausedAt === null) {
+        pausedAt = performance.now();
+    } else {
+        start += performance.now() - pausedAt;
+        pausedAt = null;
+    }
+});
+
+function draw(now) {
+    const time = (pausedAt === null ? now : pausedAt) - start;
+    const index = Math.floor(time / 1000 * framerate) % frames.length;
+    if (index !== shown) {
+        player.innerHTML = frames[index];
+        shown = index;
+    }
+    requestAnimationFrame(draw);
+}
+requestAnimationFrame(draw);
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::screen::text_cells;
+
+    fn header(title: &str) -> Header {
+        Header {
+            framerate: 10,
+            width: 3,
+            height: 2,
+            renderer: None,
+            color_depth: None,
+            char_height: Some(2.0),
+            title: Some(title.to_string()),
+            duration: None,
+            keyframe_interval: None,
+        }
+    }
+
+    /// `<&` and a red `"` on top, and a bold `x>` under them.
+    fn frame() -> Frame {
+        let mut top = text_cells("<&\"");
+        top[2].fg = Some(TermColor::Rgb([255, 0, 0]));
+        let bottom = text_cells("x>").into_iter().map(Cell::bold).collect();
+        Frame { cells: vec![top, bottom], ..Frame::default() }
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(escape_xml("<b>Tom & \"Jerry\"</b>'s"), "&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;'s");
+        assert_eq!(escape_xml("█ plain ▀"), "█ plain ▀");
+    }
+
+    #[test]
+    fn frame_markup() {
+        assert_eq!(
+            frame_html(&frame(), &CssPalette::new()),
+            "&lt;&amp;<span style=\"color:#ff0000\">&quot;</span>\n<span style=\"font-weight:bold\">x&gt;</span>"
+        );
+    }
+
+    #[test]
+    fn page() {
+        let path = std::env::temp_dir().join(format!("unicode_player_{}.html", std::process::id()));
+        let mut writer = HtmlWriter::create(path.to_str().unwrap(), &header("Tom & <Jerry>")).unwrap();
+        writer.write_frame(frame()).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let page = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(page.contains("<title>Tom &amp; &lt;Jerry&gt;</title>"));
+        assert!(page.contains("<h1>Tom &amp; &lt;Jerry&gt;</h1>"));
+        assert!(page.contains("const framerate = 10;\n"));
+        assert!(page.contains(
+            "\n\"&lt;&amp;<span style=\\\"color:#ff0000\\\">&quot;</span>\\n<span style=\\\"font-weight:bold\\\">x&gt;</span>\",\n"
+        ));
+        assert!(page.ends_with("</html>\n"));
+    }
+}
@@ -0,0 +1,367 @@
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::export::Exporter;
+use crate::palette::ColorDepth;
+use crate::renderers::{RenderOptions, Renderer};
+use crate::screen::{Frame, Screen};
+use crate::source::Source;
+use crate::tui::{Area, MAX_CLIENT_SIZE};
+
+/// The most a request line and its headers can take up.
+pub(crate) const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Used for anything left out of the query.
+const DEFAULT_SIZE: (u32, u32) = (80, 24);
+const DEFAULT_COLOR_DEPTH: ColorDepth = ColorDepth::Ansi256;
+
+/// Keeps one request from making the server render something huge.
+const MAX_SIZE: u32 = MAX_CLIENT_SIZE as u32;
+
+/// What the server sends back to anything other than a good request from a terminal. The URL is
+/// quoted so the shell doesn't take the `&` for itself.
+fn usage(port: u16) -> String {
+    format!(
+        "\
+This plays a video in your terminal. Try it with curl:
+
+    curl -N 'http://<host>:{}/?w=120&h=40&mode=braille'
+
+w and h are the size in characters (80x24 by default), mode is any of the renderers from
+`unicode_player --help`, and colors is truecolor, 256, 16 or mono (256 by default).
+",
+        port
+    )
+}
+
+/// Streams `path` to anyone who fetches it with curl on `port`, each at their own size and in
+/// their own renderer.
+pub(crate) fn serve(port: u16, path: &str, options: RenderOptions, framerate: u32) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    eprintln!("Playing {} over HTTP on port {}", path, port);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let path = path.to_string();
+        thread::Builder::new()
+            .name("http client".to_string())
+            .spawn(move || {
+                if let Err(err) = handle_client(stream, &path, options, framerate) {
+                    eprintln!("HTTP client stopped: {}", err);
+                }
+            })?;
+    }
+    Ok(())
+}
+
+/// What a client asked for.
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// The value of the header called `name`, ignoring case, or an empty string if it wasn't sent.
+    pub(crate) fn header(&self, name: &str) -> &str {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map_or("", |(_, value)| value)
+    }
+}
+
+fn handle_client(
+    stream: TcpStream,
+    path: &str,
+    mut options: RenderOptions,
+    framerate: u32,
+) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let usage = usage(stream.local_addr()?.port());
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_SIZE);
+    let mut out = BufWriter::new(stream);
+
+    let request = match read_request(&mut reader)? {
+        Some(request) => request,
+        None => return send_text(&mut out, "400 Bad Request", "That doesn't look like an HTTP request.\n"),
+    };
+    if request.method != "GET" {
+        return send_text(&mut out, "405 Method Not Allowed", &usage);
+    }
+    if request.path != "/" {
+        return send_text(&mut out, "404 Not Found", &usage);
+    }
+    // The stream is only any good in a terminal
+    if !is_terminal_client(request.header("User-Agent")) {
+        return send_text(&mut out, "406 Not Acceptable", &usage);
+    }
+
+    let (area, renderer, color_depth) = match parse_query(&request.query) {
+        Ok(settings) => settings,
+        Err(err) => return send_text(&mut out, "400 Bad Request", &format!("{}\n\n{}", err, usage)),
+    };
+    options.color_depth = color_depth;
+
+    let mut source = match Source::new(Some(path), framerate, false, Duration::ZERO, None) {
+        Ok(source) => source,
+        Err(err) => return send_text(&mut out, "500 Internal Server Error", &format!("Couldn't open the video: {}\n", err)),
+    };
+
+    write!(
+        out,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Transfer-Encoding: chunked\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\r\n"
+    )?;
+    let mut writer = AnsiStreamWriter::new(out)?;
+
+    let video_size = source.video_size();
+    source.set_frame_size(renderer.frame_size(video_size, area, options.char_height))?;
+
+    while !source.finished {
+        let frame = renderer.render_player(source.next_frame(), video_size, area, options);
+        // The client hanging up is how most streams end
+        if writer.write_frame(frame).is_err() {
+            source.stop();
+            return Ok(());
+        }
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+/// Reads the request line and the headers. Returns `None` if it's not HTTP.
+pub(crate) fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+    if !version.starts_with("HTTP/") {
+        return Ok(None);
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers: Vec::new(),
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            // Ran out before the blank line, from hanging up or sending too much
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            request.headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(Some(request))
+}
+
+/// Whether the client is something that shows the response in a terminal, as opposed to a
+/// browser that would just keep loading.
+fn is_terminal_client(user_agent: &str) -> bool {
+    ["curl/", "Wget/", "HTTPie/"].iter().any(|prefix| user_agent.starts_with(prefix))
+}
+
+/// Picks the size, renderer and colors out of a query like `w=120&h=40&mode=braille`.
+fn parse_query(query: &str) -> Result<(Area, Renderer, ColorDepth), String> {
+    let (mut width, mut height) = DEFAULT_SIZE;
+    let mut renderer = Renderer::PixelChar;
+    let mut color_depth = DEFAULT_COLOR_DEPTH;
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "w" | "h" => {
+                let size = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|size| (1..=MAX_SIZE).contains(size))
+                    .ok_or_else(|| format!("{} has to be a number from 1 to {}", key, MAX_SIZE))?;
+                if key == "w" {
+                    width = size;
+                } else {
+                    height = size;
+                }
+            }
+            "mode" => {
+                renderer = Renderer::from_str(value, true).map_err(|_| format!("No mode called {:?}", value))?;
+            }
+            "colors" => {
+                color_depth = ColorDepth::from_str(value, true).map_err(|_| format!("No colors called {:?}", value))?;
+            }
+            _ => return Err(format!("Unknown parameter {:?}", key)),
+        }
+    }
+
+    Ok((Area { width, height }, renderer, color_depth))
+}
+
+pub(crate) fn send_text(out: &mut impl Write, status: &str, text: &str) -> Result<(), Box<dyn Error>> {
+    send(out, status, "text/plain; charset=utf-8", text.as_bytes())
+}
+
+/// Sends a whole response and leaves the connection to be closed.
+pub(crate) fn send(out: &mut impl Write, status: &str, content_type: &str, body: &[u8]) -> Result<(), Box<dyn Error>> {
+    write!(
+        out,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    out.write_all(body)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Sends frames as the body of a chunked response, each one moving the cursor home and then
+/// drawing what changed since the one before.
+struct AnsiStreamWriter<W: Write> {
+    out: W,
+    screen: Screen,
+    /// How many rows the last frame took up.
+    height: usize,
+}
+
+impl<W: Write> AnsiStreamWriter<W> {
+    fn new(out: W) -> io::Result<Self> {
+        let mut writer = Self { out, screen: Screen::new(), height: 0 };
+        // Hide the cursor and start from a blank screen
+        writer.write_chunk(b"\x1b[?25l\x1b[2J")?;
+        Ok(writer)
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        write!(self.out, "{:x}\r\n", data.len())?;
+        self.out.write_all(data)?;
+        self.out.write_all(b"\r\n")?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Exporter for AnsiStreamWriter<W> {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        self.height = frame.cells.len();
+        let output = self.screen.draw(frame);
+        // The same frame is handed over again while waiting for the next one, and an empty chunk
+        // would end the response
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.write_chunk(format!("\x1b[H{}", output).as_bytes())
+    }
+
+    /// Puts the cursor back below the video and ends the response.
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_chunk(format!("\x1b[{};1H\x1b[?25h", self.height + 1).as_bytes())?;
+        self.out.write_all(b"0\r\n\r\n")?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(query: &str) -> (u32, u32, Renderer, ColorDepth) {
+        let (area, renderer, color_depth) = parse_query(query).unwrap();
+        (area.width, area.height, renderer, color_depth)
+    }
+
+    #[test]
+    fn query_defaults() {
+        for query in ["", "&", "&&"] {
+            let (width, height, renderer, color_depth) = settings(query);
+            assert_eq!((width, height), DEFAULT_SIZE);
+            assert!(matches!(renderer, Renderer::PixelChar));
+            assert!(color_depth == DEFAULT_COLOR_DEPTH);
+        }
+    }
+
+    #[test]
+    fn query_settings() {
+        let (width, height, renderer, color_depth) = settings("w=120&h=40&mode=braille&colors=truecolor");
+        assert_eq!((width, height), (120, 40));
+        assert!(matches!(renderer, Renderer::Braille));
+        assert!(color_depth == ColorDepth::Truecolor);
+
+        // Whatever comes last wins, and the rest stays at the default
+        let (width, height, _, _) = settings("w=10&w=20");
+        assert_eq!((width, height), (20, DEFAULT_SIZE.1));
+    }
+
+    #[test]
+    fn query_bounds() {
+        assert_eq!(settings("w=1&h=1").0, 1);
+        assert_eq!(settings("w=1000&h=1000").1, 1000);
+        for query in ["w=0", "h=0", "w=1001", "h=1001", "w=", "h", "w=-1", "w=ten"] {
+            let err = parse_query(query).err().unwrap();
+            assert!(err.ends_with("has to be a number from 1 to 1000"), "{}: {}", query, err);
+        }
+    }
+
+    #[test]
+    fn query_unknown() {
+        assert_eq!(parse_query("w=80&size=big").err().unwrap(), "Unknown parameter \"size\"");
+        assert_eq!(parse_query("mode=teletext").err().unwrap(), "No mode called \"teletext\"");
+        assert_eq!(parse_query("colors=8").err().unwrap(), "No colors called \"8\"");
+    }
+
+    #[test]
+    fn request() {
+        let mut input =
+            "GET /?w=120&h=40 HTTP/1.1\r\nHost: example.com:8080\r\nuser-agent:  curl/8.5.0 \r\nbroken\r\n\r\nbody".as_bytes();
+        let request = read_request(&mut input).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/");
+        assert_eq!(request.query, "w=120&h=40");
+        assert_eq!(request.header("host"), "example.com:8080");
+        assert_eq!(request.header("User-Agent"), "curl/8.5.0");
+        assert_eq!(request.header("Accept"), "");
+        // The body is left to be read
+        assert_eq!(input, b"body");
+
+        let request = read_request(&mut "GET /stream HTTP/1.0\n\n".as_bytes()).unwrap().unwrap();
+        assert_eq!(request.path, "/stream");
+        assert_eq!(request.query, "");
+    }
+
+    #[test]
+    fn not_a_request() {
+        let inputs = ["", "\r\n\r\n", "GET /\r\n\r\n", "SSH-2.0-OpenSSH_9.6 x y\r\n\r\n", "GET / HTTP/1.1\r\nHost: x\r\n"];
+        for input in inputs {
+            assert!(read_request(&mut input.as_bytes()).unwrap().is_none(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn usage_url() {
+        assert!(usage(8080).contains("curl -N 'http://<host>:8080/?w=120&h=40&mode=braille'"));
+    }
+}
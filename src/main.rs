@@ -1,25 +1,47 @@
 use std::collections::VecDeque;
-use std::fs::File;
 use std::io::prelude::*;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, Sender};
 use std::sync::Mutex;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use clap::Parser;
-use image::io::Reader as ImageReader;
+use clap::{Parser, Subcommand};
 use lazy_static::lazy_static;
 
-use renderers::Renderer;
+use audio::{AudioBackend, AudioOutput};
+use dither::Dither;
+use export::{ExportFormat, ExportOptions};
+use palette::ColorDepth;
+use renderers::{ColorFit, RenderOptions, Renderer};
 
+use crate::audio::Clock;
+use crate::recording::{Header, Recording};
 use crate::source::Source;
-use crate::terminal::{TermEvent, TermUtility, Terminal};
-use crate::tui::{Area, EventResponse, Tui};
+use crate::screen::{Frame, Screen};
+use crate::terminal::{TermEvent, TermUtility, TermWriter, Terminal};
+use crate::tui::{Area, EventResponse, Stats, Tui};
 
+mod ans;
+mod audio;
+mod bbs;
+mod cast;
+mod color;
+mod dither;
+mod export;
+mod graphics;
+mod html;
+mod http;
+mod palette;
+mod recording;
 mod renderers;
+mod screen;
 mod source;
+mod svg;
+mod telnet;
 mod terminal;
 mod tui;
+mod web;
 mod youtube;
 
 lazy_static! {
@@ -27,7 +49,10 @@ lazy_static! {
 }
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     // #[clap(validator = file_exists)]
     filename: Option<String>,
     #[arg(short, long, default_value_t = 30)]
@@ -36,12 +61,86 @@ struct Cli {
     char_height: f32,
     #[arg(short, long, value_enum, default_value_t = Renderer::PixelChar)]
     mode: Renderer,
+    /// How cells with several subpixels pick their two colors
+    #[arg(long, value_enum, default_value_t = ColorFit::Luma)]
+    fit: ColorFit,
+    /// Dithering applied to the scaled video before it's turned into characters
+    #[arg(long, value_enum, default_value_t = Dither::None)]
+    dither: Dither,
+    /// Colors available in the terminal. Detected from COLORTERM and TERM when playing, and
+    /// truecolor when writing to `--output`
+    #[arg(long, value_enum)]
+    color_depth: Option<ColorDepth>,
+    /// With 256 or 16 colors, load a palette picked for each frame into the terminal
+    #[arg(long)]
+    adaptive_palette: bool,
+    /// Where to play the sound. The video keeps time with it
+    #[arg(long, value_enum, default_value_t = AudioBackend::Pulse)]
+    audio: AudioBackend,
+    /// The file written by `--audio wav`
+    #[arg(long, default_value = "unicode_player.wav")]
+    wav_path: PathBuf,
+    /// How many threads render rows of the frame, all cores by default
+    #[arg(long)]
+    threads: Option<usize>,
     #[arg(short, long, requires = "size", requires = "filename")]
     output: Option<String>,
+    /// What to write to `--output`
+    #[arg(long, value_enum, default_value_t = ExportFormat::Recording, requires = "output")]
+    format: ExportFormat,
     #[arg(short, long, requires = "output", value_parser = parse_dims)]
     size: Option<(u32, u32)>,
+    /// Leave out the index at the end of `--output`, which lets players seek without reading
+    /// through every frame
+    #[arg(long, requires = "output")]
+    no_index: bool,
+    /// Store every Nth frame of `--output` whole, and only the cells that changed in the frames
+    /// between. 1 stores every frame whole
+    #[arg(long, default_value_t = 1, requires = "output", value_parser = clap::value_parser!(u32).range(1..))]
+    keyframe_interval: u32,
+    /// The author written to the SAUCE record of `--format ans` and `--format ans-frames`
+    #[arg(long, default_value = "", requires = "output")]
+    author: String,
+    /// The largest `--format bbs-script` can be, in bytes. Bigger scripts are stored as delta
+    /// frames, then at lower framerates, until they fit
+    #[arg(long, default_value_t = 1 << 20, requires = "output")]
+    max_script_size: usize,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Play a file written with `--output`
+    PlayRecording {
+        file: String,
+        /// Start over from the beginning when the recording ends
+        #[arg(long = "loop")]
+        looping: bool,
+    },
+    /// Play a file to anyone who connects with telnet, sized to fit their terminal
+    ServeTelnet {
+        file: String,
+        #[arg(long, default_value_t = 2323)]
+        port: u16,
+        /// Colors to send to every client, instead of picking them from the terminal type each one
+        /// sends
+        #[arg(long, value_enum)]
+        color_depth: Option<ColorDepth>,
+    },
+    /// Stream a file to anyone who fetches it with curl, at the size and in the mode they ask for
+    ServeHttp {
+        file: String,
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+    },
+    /// Serve a web page that plays a file in xterm.js, with one stream shared by every viewer
+    ServeWeb {
+        file: String,
+        #[arg(long, default_value_t = 8000)]
+        port: u16,
+    },
+}
+
+#[allow(dead_code)]
 fn file_exists(filename: &str) -> Result<(), String> {
     if std::path::Path::new(filename).is_file() {
         Ok(())
@@ -65,34 +164,84 @@ fn parse_dims(dims: &str) -> Result<(u32, u32), String> {
 fn main() {
     let cli = Cli::parse();
 
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+
+    let renderer = cli.mode;
+    let options = RenderOptions {
+        char_height: cli.char_height,
+        color_fit: cli.fit,
+        dither: cli.dither,
+        // The terminal the player runs in says nothing about where an exported file is shown
+        color_depth: cli.color_depth.unwrap_or_else(|| {
+            if cli.output.is_some() { ColorDepth::Truecolor } else { ColorDepth::detect() }
+        }),
+        adaptive_palette: cli.adaptive_palette,
+    };
+
+    if let Some(Command::PlayRecording { file, looping }) = &cli.command {
+        play_recording(file, *looping, options);
+        return;
+    }
+    if let Some(Command::ServeTelnet { file, port, color_depth }) = &cli.command {
+        telnet::serve(*port, file, renderer, options, *color_depth, cli.framerate).unwrap();
+        return;
+    }
+    if let Some(Command::ServeHttp { file, port }) = &cli.command {
+        http::serve(*port, file, options, cli.framerate).unwrap();
+        return;
+    }
+    if let Some(Command::ServeWeb { file, port }) = &cli.command {
+        web::serve(*port, file, renderer, options, cli.framerate).unwrap();
+        return;
+    }
+
+    // Exporting reads frames as fast as they decode, so there's nothing to play audio along with
+    let audio_output = cli.output.is_none().then(|| AudioOutput::new(cli.audio, cli.wav_path.clone()));
+
     let mut source = Source::new(
         cli.filename.as_deref(),
         cli.framerate,
         cli.output.is_some(),
+        Duration::ZERO,
+        audio_output.as_ref(),
     ).unwrap();
 
-    let renderer = cli.mode;
-
     if let Some(output) = cli.output {
-        let mut file = File::create(output).unwrap();
-
         let size = cli.size.unwrap();
         let area = Area { width: size.0, height: size.1 };
 
-        write!(
-            file,
-            "{{\"framerate\": {}, \"width\": {}, \"height\": {}}}",
-            cli.framerate,
-            size.0,
-            size.1,
-        ).unwrap();
+        let header = Header {
+            framerate: cli.framerate,
+            width: size.0,
+            height: size.1,
+            renderer: Some(renderer),
+            color_depth: Some(options.color_depth),
+            char_height: Some(options.char_height),
+            title: source.title().or(cli.filename.as_deref()).map(str::to_string),
+            duration: source.duration().map(|duration| duration.as_secs_f64()),
+            keyframe_interval: Some(cli.keyframe_interval),
+        };
+        let export_options = ExportOptions {
+            index: !cli.no_index,
+            author: cli.author,
+            max_script_size: cli.max_script_size,
+        };
+        let mut writer = cli.format.create(&output, &header, &export_options).unwrap();
+
+        let video_size = source.video_size();
+        source.set_frame_size(renderer.frame_size(video_size, area, options.char_height)).unwrap();
 
         while !source.finished {
-            let frame = renderer.render_player(source.next_frame(), area, cli.char_height);
-            write!(file, "\n{}", frame.join("")).unwrap();
+            let frame = renderer.render_player(source.next_frame(), video_size, area, options);
+            writer.write_frame(frame).unwrap();
         }
 
-        file.flush().unwrap();
+        writer.finish().unwrap();
     } else {
         let mut terminal = Terminal::new_crossterm();
 
@@ -105,57 +254,215 @@ fn main() {
             })
             .unwrap();
 
-        let mut tui = Tui::new(renderer, cli.char_height, &terminal);
+        let mut tui = Tui::new(renderer, options, terminal.size());
 
         let mut frame_times = VecDeque::from([Duration::new(0, 0); 300]);
 
+        let mut screen = Screen::new();
+        let mut terminal_size = terminal.size();
+        let mut stats = Stats::default();
+
         'frame_loop: loop {
             for event in rx.try_iter() {
                 match tui.handle_event(event) {
                     EventResponse::Ok => {}
                     EventResponse::Quit => break 'frame_loop,
                     EventResponse::Restart => {
-                        source = Source::new(cli.filename.as_deref(), cli.framerate, false).unwrap();
+                        source.stop();
+                        source = Source::new(
+                            cli.filename.as_deref(),
+                            cli.framerate,
+                            false,
+                            Duration::ZERO,
+                            audio_output.as_ref(),
+                        ).unwrap();
                     }
                     EventResponse::ChangeSource(path) => {
-                        source = Source::new(Some(&path), cli.framerate, false).unwrap();
+                        source.stop();
+                        source = Source::new(
+                            Some(&path),
+                            cli.framerate,
+                            false,
+                            Duration::ZERO,
+                            audio_output.as_ref(),
+                        ).unwrap();
                     }
                     EventResponse::PlayPause => {
                         source.toggle_pause();
                     }
+                    EventResponse::Seek(offset) => {
+                        source.seek(offset).unwrap();
+                    }
+                    EventResponse::ChangeVolume(delta) => {
+                        if let Some(audio_output) = &audio_output {
+                            audio_output.change_volume(delta);
+                        }
+                    }
                 }
             }
 
+            stats.position = source.position();
+            stats.duration = source.duration();
+            stats.volume = audio_output.as_ref().map(AudioOutput::volume);
+            stats.frames_dropped = source.frames_dropped();
+            stats.frames_repeated = source.frames_repeated();
+            stats.queue_depth = source.queue_depth();
+
+            // Picks up on the player being resized or switching renderers since the last frame
+            let video_size = source.video_size();
+            source.set_frame_size(tui.frame_size(video_size)).unwrap();
             let img = source.next_frame();
 
-            let frametime_avg = frame_times.iter().sum::<Duration>() / 300;
+            stats.frame_time = frame_times.iter().sum::<Duration>() / 300;
 
             let t0 = Instant::now();
 
-            terminal.move_cursor(1, 1).unwrap();
-            terminal.clear().unwrap();
-            write!(
-                terminal,
-                "{}",
-                tui.render(
-                    img,
-                    cli.filename.as_deref().unwrap_or("None"),
-                    frametime_avg,
-                    &terminal
-                )
-            )
-                .unwrap();
-            terminal.move_cursor(tui.cursor_x(), tui.cursor_y()).unwrap();
-            terminal.flush().unwrap();
+            let frame = tui.render(
+                img,
+                video_size,
+                cli.filename.as_deref().unwrap_or("None"),
+                &stats,
+                terminal.size(),
+            );
+
+            stats.frame_bytes = draw_frame(&mut terminal, &mut screen, &mut terminal_size, frame, &tui);
 
             frame_times.pop_front();
             frame_times.push_back(Instant::now() - t0);
         }
 
+        if options.adaptive_palette {
+            terminal.reset_palette().unwrap();
+        }
+        if let Renderer::Kitty = renderer {
+            write!(terminal, "{}", graphics::delete_kitty()).unwrap();
+            terminal.flush().unwrap();
+        }
+
         evt_thread.join().unwrap();
     }
 }
 
+/// Writes what changed since the last frame to the terminal, redrawing everything if it was
+/// resized. Returns how many bytes that took.
+fn draw_frame(
+    terminal: &mut Terminal<TermWriter>,
+    screen: &mut Screen,
+    terminal_size: &mut (u16, u16),
+    frame: Frame,
+    tui: &Tui,
+) -> usize {
+    if terminal.size() != *terminal_size {
+        *terminal_size = terminal.size();
+        terminal.clear().unwrap();
+        screen.invalidate();
+    }
+
+    let output = screen.draw(frame);
+    terminal.begin_synchronized_update().unwrap();
+    write!(terminal, "{}", output).unwrap();
+    terminal.move_cursor(tui.cursor_x(), tui.cursor_y()).unwrap();
+    terminal.end_synchronized_update().unwrap();
+    terminal.flush().unwrap();
+    output.len()
+}
+
+/// Plays a file written with `--output` in the player. The frames are already rendered, so this
+/// doesn't need ffmpeg.
+fn play_recording(path: &str, looping: bool, options: RenderOptions) {
+    let mut recording = Recording::open(path).unwrap();
+    let title = recording.header.title.clone().unwrap_or_else(|| path.to_string());
+
+    let mut terminal = Terminal::new_crossterm();
+
+    let (tx, rx) = channel();
+    let evt_thread = thread::Builder::new()
+        .name("event".to_string())
+        .spawn({
+            let terminal = terminal.utility();
+            move || event_thread(terminal, tx)
+        })
+        .unwrap();
+
+    let mut tui = Tui::new(Renderer::PixelChar, options, terminal.size());
+
+    let mut frame_times = VecDeque::from([Duration::new(0, 0); 300]);
+
+    let mut screen = Screen::new();
+    let mut terminal_size = terminal.size();
+    let mut stats = Stats { duration: Some(recording.duration()), ..Stats::default() };
+
+    let frame_length = recording.timestamp(1);
+    let mut clock = Clock::without_audio(Duration::ZERO);
+    let mut paused = false;
+    let mut used_palette = false;
+    let mut used_graphics = false;
+
+    'frame_loop: loop {
+        for event in rx.try_iter() {
+            match tui.handle_event(event) {
+                EventResponse::Quit => break 'frame_loop,
+                EventResponse::Restart => {
+                    clock = Clock::without_audio(Duration::ZERO);
+                    clock.set_paused(paused);
+                }
+                EventResponse::PlayPause => {
+                    paused = !paused;
+                    clock.set_paused(paused);
+                }
+                EventResponse::Seek(offset) => {
+                    let target = (clock.now().as_secs_f32() + offset)
+                        .clamp(0.0, recording.duration().as_secs_f32());
+                    clock = Clock::without_audio(Duration::from_secs_f32(target));
+                    clock.set_paused(paused);
+                }
+                // There's no source to change and no sound in a recording
+                _ => {}
+            }
+        }
+
+        let index = match recording.index_at(clock.now()) {
+            Some(index) => index,
+            None if looping => {
+                clock = Clock::without_audio(Duration::ZERO);
+                clock.set_paused(paused);
+                0
+            }
+            // The last frame stays up once the recording is over
+            None => recording.len() - 1,
+        };
+
+        stats.position = clock.now().min(recording.duration());
+        stats.frame_time = frame_times.iter().sum::<Duration>() / 300;
+
+        let t0 = Instant::now();
+
+        let recorded = recording.frame(index).unwrap();
+        used_palette |= !recorded.prelude.is_empty();
+        used_graphics |= !recorded.graphics.is_empty();
+        let frame = tui.render_recorded(recorded, recording.header.renderer, &title, &stats, terminal.size());
+
+        stats.frame_bytes = draw_frame(&mut terminal, &mut screen, &mut terminal_size, frame, &tui);
+
+        frame_times.pop_front();
+        frame_times.push_back(Instant::now() - t0);
+
+        // While paused the clock doesn't move, so this still wakes up in time to handle input
+        let next = recording.timestamp(index + 1);
+        thread::sleep(next.saturating_sub(clock.now()).min(frame_length));
+    }
+
+    if used_palette {
+        terminal.reset_palette().unwrap();
+    }
+    if used_graphics {
+        write!(terminal, "{}", graphics::delete_kitty()).unwrap();
+        terminal.flush().unwrap();
+    }
+
+    evt_thread.join().unwrap();
+}
+
 fn event_thread(term: Terminal<TermUtility>, tx: Sender<TermEvent>) {
     for (stop_after, event) in term.events() {
         tx.send(event).unwrap();
@@ -0,0 +1,299 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+use image::{Rgb, RgbImage};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::color::{Oklab, CUBE_LEVELS};
+use crate::screen::Cell;
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorDepth {
+    Truecolor,
+    #[value(name = "256")]
+    #[serde(rename = "256")]
+    Ansi256,
+    #[value(name = "16")]
+    #[serde(rename = "16")]
+    Ansi16,
+    Mono,
+}
+
+impl ColorDepth {
+    /// Guesses what the terminal supports from `COLORTERM` and `TERM`.
+    pub(crate) fn detect() -> Self {
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        let term = std::env::var("TERM").unwrap_or_default();
+        Self::from_term(&colorterm, &term)
+    }
+
+    /// Guesses what a terminal supports from what `COLORTERM` and `TERM` would be set to.
+    pub(crate) fn from_term(colorterm: &str, term: &str) -> Self {
+        if colorterm == "truecolor" || colorterm == "24bit" || term.ends_with("-direct") {
+            Self::Truecolor
+        } else if term.contains("256") {
+            Self::Ansi256
+        } else if term.is_empty() || term == "dumb" {
+            Self::Mono
+        } else {
+            Self::Ansi16
+        }
+    }
+}
+
+/// A color as it's sent to the terminal.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TermColor {
+    Rgb([u8; 3]),
+    Indexed(u8),
+}
+
+impl TermColor {
+    fn sgr(&self, base: u8, bright_base: u8, extended: u8) -> String {
+        match self {
+            Self::Rgb([r, g, b]) => format!("{};2;{};{};{}", extended, r, g, b),
+            Self::Indexed(i @ 0..=7) => (base + i).to_string(),
+            Self::Indexed(i @ 8..=15) => (bright_base + i - 8).to_string(),
+            Self::Indexed(i) => format!("{};5;{}", extended, i),
+        }
+    }
+
+    pub(crate) fn fg_sgr(&self) -> String {
+        self.sgr(30, 90, 38)
+    }
+
+    pub(crate) fn bg_sgr(&self) -> String {
+        self.sgr(40, 100, 48)
+    }
+}
+
+/// The default xterm values for the 16 ANSI colors.
+pub(crate) const ANSI16: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0xcd, 0x00, 0x00], [0x00, 0xcd, 0x00], [0xcd, 0xcd, 0x00],
+    [0x00, 0x00, 0xee], [0xcd, 0x00, 0xcd], [0x00, 0xcd, 0xcd], [0xe5, 0xe5, 0xe5],
+    [0x7f, 0x7f, 0x7f], [0xff, 0x00, 0x00], [0x00, 0xff, 0x00], [0xff, 0xff, 0x00],
+    [0x5c, 0x5c, 0xff], [0xff, 0x00, 0xff], [0x00, 0xff, 0xff], [0xff, 0xff, 0xff],
+];
+
+/// The RGB value of an xterm palette entry, assuming the terminal hasn't changed it.
+pub(crate) fn xterm_color(index: u8) -> Rgb<u8> {
+    match index {
+        0..=15 => Rgb(ANSI16[index as usize]),
+        16..=231 => {
+            let i = (index - 16) as usize;
+            Rgb([CUBE_LEVELS[i / 36], CUBE_LEVELS[i / 6 % 6], CUBE_LEVELS[i % 6]])
+        }
+        _ => {
+            let level = 8 + 10 * (index - 232);
+            Rgb([level, level, level])
+        }
+    }
+}
+
+/// The closest of the 16 ANSI colors, for formats that can't show any others.
+pub(crate) fn nearest_ansi16(color: &Rgb<u8>) -> u8 {
+    XTERM_16_LUT[lut_key(color)]
+}
+
+// Colors are looked up by their top 5 bits per channel
+fn lut_key(color: &Rgb<u8>) -> usize {
+    ((color[0] as usize >> 3) << 10) | ((color[1] as usize >> 3) << 5) | (color[2] as usize >> 3)
+}
+
+fn build_lut(palette: &Palette) -> Vec<u8> {
+    (0..32768)
+        .map(|key| {
+            let color = Rgb([(key >> 10 << 3) as u8 | 4, ((key >> 5 & 31) << 3) as u8 | 4, ((key & 31) << 3) as u8 | 4]);
+            palette.search(&color)
+        })
+        .collect()
+}
+
+lazy_static! {
+    // The first 16 colors are often themed by the user, so only the cube and grays are used
+    static ref XTERM_256: Palette = Palette::new((16..=255).map(xterm_color).collect(), 16);
+    static ref XTERM_256_LUT: Vec<u8> = build_lut(&XTERM_256);
+    static ref XTERM_16: Palette = Palette::new(ANSI16.iter().map(|c| Rgb(*c)).collect(), 0);
+    static ref XTERM_16_LUT: Vec<u8> = build_lut(&XTERM_16);
+}
+
+/// A set of indexed terminal colors, and a perceptual nearest-color search over them.
+pub(crate) struct Palette {
+    colors: Vec<Rgb<u8>>,
+    lab: Vec<Oklab>,
+    first_index: u8,
+}
+
+impl Palette {
+    fn new(colors: Vec<Rgb<u8>>, first_index: u8) -> Self {
+        Self {
+            lab: colors.iter().map(Oklab::from_rgb).collect(),
+            colors,
+            first_index,
+        }
+    }
+
+    fn search(&self, color: &Rgb<u8>) -> u8 {
+        let lab = Oklab::from_rgb(color);
+        let nearest = self
+            .lab
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.distance_squared(&lab).total_cmp(&b.distance_squared(&lab)))
+            .unwrap()
+            .0;
+        self.first_index + nearest as u8
+    }
+
+    /// Builds a palette of `size` colors for `img` with median cut, to be loaded into the
+    /// terminal starting at `first_index`.
+    fn adaptive(img: &RgbImage, size: usize, first_index: u8) -> Self {
+        let mut boxes = vec![img.pixels().copied().collect::<Vec<Rgb<u8>>>()];
+
+        while boxes.len() < size {
+            let (i, channel, range) = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| b.len() > 1)
+                .flat_map(|(i, b)| {
+                    (0..3).map(move |c| {
+                        let min = b.iter().map(|px| px[c]).min().unwrap();
+                        let max = b.iter().map(|px| px[c]).max().unwrap();
+                        (i, c, max - min)
+                    })
+                })
+                .max_by_key(|(_, _, range)| *range)
+                .unwrap_or((0, 0, 0));
+            if range == 0 {
+                break;
+            }
+
+            let mut split = boxes.swap_remove(i);
+            split.sort_unstable_by_key(|px| px[channel]);
+            let upper = split.split_off(split.len() / 2);
+            boxes.push(split);
+            boxes.push(upper);
+        }
+
+        let colors = boxes
+            .iter()
+            .filter(|b| !b.is_empty())
+            .map(|b| {
+                let mut sum = [0u64; 3];
+                for px in b {
+                    for c in 0..3 {
+                        sum[c] += px[c] as u64;
+                    }
+                }
+                Rgb(sum.map(|s| (s / b.len() as u64) as u8))
+            })
+            .collect();
+
+        Self::new(colors, first_index)
+    }
+
+    /// The OSC 4 sequences that load this palette into the terminal.
+    fn definition(&self) -> String {
+        let mut out = String::new();
+        for (i, color) in self.colors.iter().enumerate() {
+            write!(
+                out,
+                "\x1b]4;{};rgb:{:02x}/{:02x}/{:02x}\x1b\\",
+                self.first_index as usize + i,
+                color[0],
+                color[1],
+                color[2]
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Maps the colors picked by a renderer onto what the terminal can show.
+pub(crate) enum ColorMap {
+    Truecolor,
+    Fixed(&'static Palette, &'static [u8]),
+    Adaptive(Palette),
+    Mono,
+}
+
+impl ColorMap {
+    pub(crate) fn new(depth: ColorDepth, adaptive: bool, img: &RgbImage) -> Self {
+        match (depth, adaptive) {
+            (ColorDepth::Truecolor, _) => Self::Truecolor,
+            (ColorDepth::Ansi256, false) => Self::Fixed(&XTERM_256, &XTERM_256_LUT),
+            (ColorDepth::Ansi256, true) => Self::Adaptive(Palette::adaptive(img, 240, 16)),
+            (ColorDepth::Ansi16, false) => Self::Fixed(&XTERM_16, &XTERM_16_LUT),
+            (ColorDepth::Ansi16, true) => Self::Adaptive(Palette::adaptive(img, 16, 0)),
+            (ColorDepth::Mono, _) => Self::Mono,
+        }
+    }
+
+    pub(crate) fn map(&self, color: &Rgb<u8>) -> TermColor {
+        match self {
+            Self::Truecolor => TermColor::Rgb(color.0),
+            Self::Fixed(_, lut) => TermColor::Indexed(lut[lut_key(color)]),
+            Self::Adaptive(palette) => TermColor::Indexed(palette.search(color)),
+            Self::Mono => TermColor::Indexed(if is_light(color) { 15 } else { 0 }),
+        }
+    }
+
+    /// The closest color that can actually be shown, used as the target for dithering.
+    pub(crate) fn quantize(&self, color: Rgb<u8>) -> Rgb<u8> {
+        match self {
+            Self::Truecolor => color,
+            Self::Fixed(palette, lut) => palette.colors[(lut[lut_key(&color)] - palette.first_index) as usize],
+            Self::Adaptive(palette) => palette.colors[(palette.search(&color) - palette.first_index) as usize],
+            Self::Mono => Rgb(ANSI16[if is_light(&color) { 15 } else { 0 }]),
+        }
+    }
+
+    /// Roughly the distance between neighbouring colors, which sets the strength of ordered
+    /// dithering. Truecolor has nothing to dither to.
+    pub(crate) fn spread(&self) -> f32 {
+        match self {
+            Self::Truecolor => 0.0,
+            Self::Fixed(palette, _) if palette.colors.len() > 16 => 51.0,
+            Self::Adaptive(palette) if palette.colors.len() > 16 => 51.0,
+            Self::Fixed(..) | Self::Adaptive(_) => 128.0,
+            Self::Mono => 255.0,
+        }
+    }
+
+    /// Anything that has to be sent before the frame, which is the palette itself when it's
+    /// picked per frame.
+    pub(crate) fn prelude(&self) -> String {
+        match self {
+            Self::Adaptive(palette) => palette.definition(),
+            _ => String::new(),
+        }
+    }
+
+    /// Paints `glyph` in the given colors. Without colors, black and white are faked with the
+    /// glyph itself and reverse video.
+    pub(crate) fn paint(&self, glyph: char, fg: &Rgb<u8>, bg: Option<&Rgb<u8>>) -> Cell {
+        if let Self::Mono = self {
+            let fg_light = is_light(fg);
+            let bg_light = bg.is_some_and(is_light);
+            return match (fg_light, bg_light) {
+                (true, true) => Cell::plain('█'),
+                (false, false) => Cell::BLANK,
+                (true, false) => Cell::plain(glyph),
+                (false, true) => Cell { reverse: true, ..Cell::plain(glyph) },
+            };
+        }
+
+        Cell {
+            fg: Some(self.map(fg)),
+            bg: bg.map(|bg| self.map(bg)),
+            ..Cell::plain(glyph)
+        }
+    }
+}
+
+fn is_light(color: &Rgb<u8>) -> bool {
+    0.2126 * (color[0] as f32) + 0.7152 * (color[1] as f32) + 0.0722 * (color[2] as f32) >= 128.0
+}
@@ -0,0 +1,412 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::export::Exporter;
+use crate::palette::ColorDepth;
+use crate::renderers::Renderer;
+use crate::screen::{apply_ansi, Cell, Frame, Screen};
+
+// A recording starts with `MAGIC` and the format version, then the JSON header. Each frame is
+// UTF-8 text as written by `Screen::draw`, and the frames end with a length of `END_OF_FRAMES`. The header and every frame
+// are prefixed with their length. After that comes an optional index: the offset of every frame
+// as a u64, then the offset of the index itself and `INDEX_MAGIC`. All numbers are little endian.
+
+/// Starts every recording, so it can be told apart from the old exports with one frame per line.
+const MAGIC: &[u8; 8] = b"UNIPLAY\n";
+
+/// Ends the index, right after where it starts.
+const INDEX_MAGIC: &[u8; 8] = b"UPINDEX\n";
+
+/// Goes up whenever a change to the layout or the header would trip up older readers. The old
+/// line-based exports count as version 1.
+const FORMAT_VERSION: u32 = 2;
+
+/// Takes the place of a frame length after the last frame.
+const END_OF_FRAMES: u32 = u32::MAX;
+
+/// Everything known about how a recording was made.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Header {
+    pub(crate) framerate: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    // The old exports only have the three fields above
+    pub(crate) renderer: Option<Renderer>,
+    pub(crate) color_depth: Option<ColorDepth>,
+    pub(crate) char_height: Option<f32>,
+    pub(crate) title: Option<String>,
+    /// The length of the source in seconds, if it was known.
+    pub(crate) duration: Option<f64>,
+    /// Every this many frames, one is drawn from a blank screen. The ones in between only hold
+    /// the cells that changed since the frame before. Without it, every frame is whole.
+    pub(crate) keyframe_interval: Option<u32>,
+}
+
+/// Writes a recording one frame at a time.
+pub(crate) struct RecordingWriter {
+    file: BufWriter<File>,
+    /// How much has been written so far, which is where the next frame starts.
+    position: u64,
+    offsets: Vec<u64>,
+    index: bool,
+    screen: Screen,
+    keyframe_interval: usize,
+}
+
+impl RecordingWriter {
+    pub(crate) fn create(path: &str, header: &Header, index: bool) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            position: 0,
+            offsets: Vec::new(),
+            index,
+            screen: Screen::repeating(),
+            keyframe_interval: header.keyframe_interval.unwrap_or(1).max(1) as usize,
+        };
+        writer.write_bytes(MAGIC)?;
+        writer.write_bytes(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_chunk(&serde_json::to_vec(header)?)?;
+        Ok(writer)
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(data.len())
+            .ok()
+            .filter(|len| *len != END_OF_FRAMES)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Frame is too large to record"))?;
+        self.write_bytes(&len.to_le_bytes())?;
+        self.write_bytes(data)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl Exporter for RecordingWriter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        if self.offsets.len().is_multiple_of(self.keyframe_interval) {
+            self.screen.invalidate();
+        }
+        let text = self.screen.draw(frame);
+
+        self.offsets.push(self.position);
+        self.write_chunk(text.as_bytes())
+    }
+
+    /// Marks the end of the frames and writes the index. A recording that was never finished can
+    /// still be played up to its last whole frame.
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_bytes(&END_OF_FRAMES.to_le_bytes())?;
+        if self.index {
+            let index_start = self.position;
+            for offset in std::mem::take(&mut self.offsets) {
+                self.write_bytes(&offset.to_le_bytes())?;
+            }
+            self.write_bytes(&index_start.to_le_bytes())?;
+            self.write_bytes(INDEX_MAGIC)?;
+        }
+        self.file.flush()
+    }
+}
+
+/// A file written with `--output`, read a frame at a time.
+pub(crate) struct Recording {
+    pub(crate) header: Header,
+    frames: Frames,
+    /// The last frame that was rebuilt, which the next one can usually start from.
+    last: Option<(usize, Frame)>,
+}
+
+enum Frames {
+    /// An old export, which is read whole.
+    Lines(Vec<String>),
+    /// Where each frame starts in the file.
+    File { file: BufReader<File>, offsets: Vec<u64> },
+}
+
+impl Recording {
+    pub(crate) fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Self::open_lines(path);
+        }
+
+        let version = read_u32(&mut file)?;
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "Recording is format version {}, but only version {} can be played",
+                version, FORMAT_VERSION
+            ).into());
+        }
+
+        let mut header = vec![0; read_u32(&mut file)? as usize];
+        file.read_exact(&mut header)?;
+        let header = serde_json::from_slice(&header)?;
+        let frames_start = file.stream_position()?;
+
+        let offsets = match read_index(&mut file)? {
+            Some(offsets) => offsets,
+            None => scan_frames(&mut file, frames_start)?,
+        };
+
+        Self::new(header, Frames::File { file, offsets })
+    }
+
+    /// Reads an export from before recordings had a version: a JSON header line, then one frame
+    /// per line.
+    fn open_lines(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.split('\n');
+
+        let header = serde_json::from_str(lines.next().ok_or("Empty recording")?)?;
+        let frames = lines.map(str::to_string).collect();
+
+        Self::new(header, Frames::Lines(frames))
+    }
+
+    fn new(header: Header, frames: Frames) -> Result<Self, Box<dyn Error>> {
+        if header.framerate == 0 || header.width == 0 || header.height == 0 {
+            return Err("Recording header has a framerate or size of 0".into());
+        }
+
+        let recording = Self { header, frames, last: None };
+        if recording.len() == 0 {
+            return Err("Recording has no frames".into());
+        }
+        Ok(recording)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.frames {
+            Frames::Lines(lines) => lines.len(),
+            Frames::File { offsets, .. } => offsets.len(),
+        }
+    }
+
+    pub(crate) fn duration(&self) -> Duration {
+        self.timestamp(self.len())
+    }
+
+    /// When frame `index` is shown.
+    pub(crate) fn timestamp(&self, index: usize) -> Duration {
+        Duration::from_secs_f64(index as f64 / self.header.framerate as f64)
+    }
+
+    /// The frame that's showing at `time`, if it's before the end.
+    pub(crate) fn index_at(&self, time: Duration) -> Option<usize> {
+        let index = (time.as_secs_f64() * self.header.framerate as f64) as usize;
+        (index < self.len()).then_some(index)
+    }
+
+    /// Rebuilds frame `index` from the keyframe before it.
+    pub(crate) fn frame(&mut self, index: usize) -> Result<Frame, Box<dyn Error>> {
+        let interval = self.header.keyframe_interval.unwrap_or(1).max(1) as usize;
+        let keyframe = index - index % interval;
+
+        let (start, mut frame) = match self.last.take() {
+            Some((last, frame)) if (keyframe..=index).contains(&last) => (last + 1, frame),
+            _ => (keyframe, Frame::default()),
+        };
+
+        let (width, height) = (self.header.width as usize, self.header.height as usize);
+        for i in start..=index {
+            let text = self.read_frame(i)?;
+            // Each frame sends its own palette and images again
+            frame.prelude.clear();
+            frame.graphics.clear();
+            apply_ansi(&mut frame, &text, width);
+        }
+        frame.cells.resize(height, vec![Cell::BLANK; width]);
+
+        self.last = Some((index, frame.clone()));
+        Ok(frame)
+    }
+
+    fn read_frame(&mut self, index: usize) -> Result<String, Box<dyn Error>> {
+        Ok(match &mut self.frames {
+            Frames::Lines(lines) => lines[index].clone(),
+            Frames::File { file, offsets } => {
+                file.seek(SeekFrom::Start(offsets[index]))?;
+                let mut text = vec![0; read_u32(file)? as usize];
+                file.read_exact(&mut text)?;
+                String::from_utf8(text)?
+            }
+        })
+    }
+}
+
+/// Reads the index at the end of the file, if the recording has one.
+fn read_index(file: &mut BufReader<File>) -> io::Result<Option<Vec<u64>>> {
+    let trailer_start = match file.seek(SeekFrom::End(0))?.checked_sub(16) {
+        Some(trailer_start) => trailer_start,
+        None => return Ok(None),
+    };
+
+    file.seek(SeekFrom::Start(trailer_start))?;
+    let index_start = read_u64(file)?;
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != INDEX_MAGIC || index_start > trailer_start || (trailer_start - index_start) % 8 != 0 {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(index_start))?;
+    (0..(trailer_start - index_start) / 8)
+        .map(|_| read_u64(file))
+        .collect::<io::Result<Vec<u64>>>()
+        .map(Some)
+}
+
+/// Finds the frames by skipping from each one to the next, which works without an index. This
+/// stops early at a frame that was cut off.
+fn scan_frames(file: &mut BufReader<File>, start: u64) -> io::Result<Vec<u64>> {
+    let end = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(start))?;
+
+    let mut offsets = Vec::new();
+    let mut offset = start;
+    while offset + 4 <= end {
+        let len = read_u32(file)?;
+        if len == END_OF_FRAMES || offset + 4 + len as u64 > end {
+            break;
+        }
+        offsets.push(offset);
+        offset += 4 + len as u64;
+        file.seek_relative(len as i64)?;
+    }
+    Ok(offsets)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::screen::{text_cells, Graphic};
+
+    const WIDTH: u32 = 6;
+    const HEIGHT: u32 = 2;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("unicode_player_{}_{}.uniplay", name, std::process::id()))
+    }
+
+    fn header(keyframe_interval: Option<u32>) -> Header {
+        Header {
+            framerate: 10,
+            width: WIDTH,
+            height: HEIGHT,
+            renderer: Some(Renderer::Braille),
+            color_depth: Some(ColorDepth::Ansi256),
+            char_height: Some(2.0),
+            title: Some("Test \"video\"".to_string()),
+            duration: Some(0.3),
+            keyframe_interval,
+        }
+    }
+
+    fn frames() -> Vec<Frame> {
+        let frame = |rows: [&str; 2]| Frame {
+            cells: rows.iter().map(|row| text_cells(row)).collect(),
+            ..Frame::default()
+        };
+        let mut frames = vec![frame(["abcdef", "ghijkl"]), frame(["abcxyz", "ghijkl"]), frame(["zzzzzz", "      "])];
+        // Sixel data is split into lines, which must not end the frame
+        frames[1].graphics.push(Graphic { x: 2, y: 1, data: "\x1bPq#0;2;0;0;0\n#0~~\n-\x1b\\".to_string() });
+        frames
+    }
+
+    fn write(name: &str, header: &Header, index: bool) -> PathBuf {
+        let path = temp_path(name);
+        let mut writer = RecordingWriter::create(path.to_str().unwrap(), header, index).unwrap();
+        for frame in frames() {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn offsets(recording: &Recording) -> &[u64] {
+        match &recording.frames {
+            Frames::File { offsets, .. } => offsets,
+            Frames::Lines(_) => panic!("Recording was read as an old export"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for index in [false, true] {
+            let path = write(&format!("round_trip_{}", index), &header(None), index);
+            let mut recording = Recording::open(path.to_str().unwrap()).unwrap();
+            fs::remove_file(path).unwrap();
+
+            let header = &recording.header;
+            assert_eq!((header.framerate, header.width, header.height), (10, WIDTH, HEIGHT));
+            assert!(matches!(header.renderer, Some(Renderer::Braille)));
+            assert!(header.color_depth == Some(ColorDepth::Ansi256));
+            assert_eq!(header.char_height, Some(2.0));
+            assert_eq!(header.title.as_deref(), Some("Test \"video\""));
+            assert_eq!(header.duration, Some(0.3));
+            assert_eq!(header.keyframe_interval, None);
+            assert_eq!(recording.len(), 3);
+
+            // Without keyframes, every frame is stored as if drawn on a blank screen
+            for (i, frame) in frames().into_iter().enumerate() {
+                assert_eq!(recording.read_frame(i).unwrap(), Screen::repeating().draw(frame));
+            }
+        }
+    }
+
+    #[test]
+    fn index_matches_scan() {
+        let with_index = write("with_index", &header(None), true);
+        let without_index = write("without_index", &header(None), false);
+        let indexed = Recording::open(with_index.to_str().unwrap()).unwrap();
+        let scanned = Recording::open(without_index.to_str().unwrap()).unwrap();
+        let index_len = fs::metadata(&with_index).unwrap().len() - fs::metadata(&without_index).unwrap().len();
+        fs::remove_file(with_index).unwrap();
+        fs::remove_file(without_index).unwrap();
+
+        assert_eq!(index_len, 3 * 8 + 16);
+        assert_eq!(offsets(&indexed), offsets(&scanned));
+    }
+
+    #[test]
+    fn indexed_seek() {
+        let path = write("indexed_seek", &header(Some(2)), true);
+        let mut recording = Recording::open(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        // Out of order, so frames are rebuilt from their keyframe and not only from the last one
+        for i in [2, 1, 0, 1] {
+            let expected = &frames()[i];
+            let frame = recording.frame(i).unwrap();
+            assert_eq!(frame.cells, expected.cells, "frame {}", i);
+            let data = |graphics: &[Graphic]| graphics.iter().map(|graphic| graphic.data.clone()).collect::<Vec<_>>();
+            assert_eq!(data(&frame.graphics), data(&expected.graphics), "frame {}", i);
+        }
+        assert_eq!(recording.index_at(Duration::from_millis(250)), Some(2));
+        assert_eq!(recording.index_at(Duration::from_millis(300)), None);
+    }
+}
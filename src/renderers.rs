@@ -1,19 +1,55 @@
 use std::iter;
 
-use colored::Colorize;
 use image::{Rgb, RgbImage};
 
 use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
+use crate::color::{quantize_to_cube, Oklab};
+use crate::dither::Dither;
+use crate::graphics::{encode_kitty, encode_sixel};
+use crate::palette::{ColorDepth, ColorMap};
+use crate::screen::{Cell, Frame, Graphic};
 use crate::tui::Area;
 
-#[derive(Copy, Clone, ValueEnum)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Renderer {
     PixelChar,
     HalfChar,
     Quarters,
+    Sextants,
     Braille,
+    Octants,
+    #[serde(rename = "bbs")]
     BBS,
+    Sixel,
+    Kitty,
+}
+
+/// How many pixels each cell is assumed to hold in the graphics modes. Kitty stretches the image
+/// over the cells anyway, but sixel images are drawn at this size.
+const GRAPHICS_CELL: (u32, u32) = (8, 16);
+
+/// How the cells of multi-subpixel renderers are split into a foreground and a background color.
+#[derive(Copy, Clone, ValueEnum)]
+pub enum ColorFit {
+    /// Use the brightest and dimmest subpixels, and split by brightness.
+    Luma,
+    /// Split by perceptual color distance (2-means in OKLab), using the mean of each half.
+    Oklab,
+}
+
+/// Settings shared by all renderers, taken from the command line.
+#[derive(Copy, Clone)]
+pub(crate) struct RenderOptions {
+    pub(crate) char_height: f32,
+    pub(crate) color_fit: ColorFit,
+    pub(crate) dither: Dither,
+    pub(crate) color_depth: ColorDepth,
+    pub(crate) adaptive_palette: bool,
 }
 
 impl Renderer {
@@ -21,19 +57,27 @@ impl Renderer {
         match self {
             Self::PixelChar => Self::HalfChar,
             Self::HalfChar => Self::Quarters,
-            Self::Quarters => Self::Braille,
-            Self::Braille => Self::PixelChar,
+            Self::Quarters => Self::Sextants,
+            Self::Sextants => Self::Braille,
+            Self::Braille => Self::Octants,
+            Self::Octants => Self::PixelChar,
             Self::BBS => Self::BBS,
+            Self::Sixel => Self::Sixel,
+            Self::Kitty => Self::Kitty,
         }
     }
 
     pub fn last_mode(&self) -> Self {
         match self {
-            Self::PixelChar => Self::Braille,
+            Self::PixelChar => Self::Octants,
             Self::HalfChar => Self::PixelChar,
             Self::Quarters => Self::HalfChar,
-            Self::Braille => Self::Quarters,
+            Self::Sextants => Self::Quarters,
+            Self::Braille => Self::Sextants,
+            Self::Octants => Self::Braille,
             Self::BBS => Self::BBS,
+            Self::Sixel => Self::Sixel,
+            Self::Kitty => Self::Kitty,
         }
     }
 
@@ -42,18 +86,30 @@ impl Renderer {
             Self::PixelChar => (1, 1),
             Self::HalfChar => (1, 2),
             Self::Quarters => (2, 2),
+            Self::Sextants => (2, 3),
             Self::Braille => (2, 4),
+            Self::Octants => (2, 4),
             Self::BBS => (2, 2),
+            Self::Sixel | Self::Kitty => GRAPHICS_CELL,
         }
     }
 
+    /// Whether the frame is sent as an image instead of as characters.
+    fn is_graphics(&self) -> bool {
+        matches!(self, Self::Sixel | Self::Kitty)
+    }
+
     pub(crate) fn name(&self) -> String {
         match self {
             Renderer::PixelChar => "Full Chars".to_string(),
             Renderer::HalfChar => "Half Chars".to_string(),
             Renderer::Quarters => "Quarters".to_string(),
+            Renderer::Sextants => "Sextants".to_string(),
             Renderer::Braille => "Braille".to_string(),
+            Renderer::Octants => "Octants".to_string(),
             Renderer::BBS => "BBS".to_string(),
+            Renderer::Sixel => "Sixel".to_string(),
+            Renderer::Kitty => "Kitty".to_string(),
         }
     }
 
@@ -109,65 +165,130 @@ impl Renderer {
         }
     }
 
-    pub(crate) fn render_player(&self, img: &RgbImage, bounds: Area, char_height: f32) -> Vec<String> {
-        let (dims, gap_x, gap_y) = self.calc_dims_fixed((img.width(), img.height()), bounds, char_height);
-        let scaled_img = image::imageops::resize(img, dims.width, dims.height, image::imageops::FilterType::Triangle);
+    /// The size, in pixels, that a video of `video_size` is scaled to before it's rendered.
+    pub(crate) fn frame_size(&self, video_size: (u32, u32), bounds: Area, char_height: f32) -> (u32, u32) {
+        let (dims, _, _) = self.calc_dims_fixed(video_size, bounds, char_height);
+        (dims.width, dims.height)
+    }
 
-        let vert_spacer = " ".repeat(bounds.width as usize);
-        let horiz_spacer = " ".repeat(gap_x as usize);
+    /// Renders `img`, a frame from a video of `video_size`. The frame may already have been
+    /// scaled to `frame_size`, in which case it's used as is.
+    pub(crate) fn render_player(&self, img: &RgbImage, video_size: (u32, u32), bounds: Area, options: RenderOptions) -> Frame {
+        let (dims, gap_x, gap_y) = self.calc_dims_fixed(video_size, bounds, options.char_height);
+        let mut scaled_img = if img.dimensions() == (dims.width, dims.height) {
+            img.clone()
+        } else {
+            image::imageops::resize(img, dims.width, dims.height, image::imageops::FilterType::Triangle)
+        };
+        let color_depth = if self.is_graphics() { ColorDepth::Truecolor } else { options.color_depth };
+        let colors = ColorMap::new(color_depth, options.adaptive_palette, &scaled_img);
+        options.dither.apply(&mut scaled_img, self.dither_spread(&colors), |color| self.dither_quantize(color, &colors));
 
-        iter::repeat(vert_spacer.clone())
-            .take(gap_y as usize)
-            .chain(
-                (0..dims.height)
-                    .step_by(self.subpixels().1 as usize)
-                    .map(|i| format!(
-                        "{}{}{}",
-                        horiz_spacer,
-                        (0..dims.width)
-                            .step_by(self.subpixels().0 as usize)
-                            .map(|j| self.render_pixel(&scaled_img, (j, i))).collect::<String>(),
-                        horiz_spacer,
-                    ))
+        if self.is_graphics() {
+            let data = match self {
+                Self::Sixel => encode_sixel(&scaled_img),
+                _ => encode_kitty(
+                    &scaled_img,
+                    dims.width / self.subpixels().0,
+                    dims.height / self.subpixels().1,
+                ),
+            };
+            return Frame {
+                prelude: String::new(),
+                cells: vec![vec![Cell::BLANK; bounds.width as usize]; bounds.height as usize],
+                graphics: vec![Graphic { x: gap_x, y: gap_y, data }],
+            };
+        }
+
+        let vert_spacer = vec![Cell::BLANK; bounds.width as usize];
+        let horiz_spacer = iter::repeat_n(Cell::BLANK, gap_x as usize);
+
+        // Rows don't depend on each other, and collecting them keeps them in order, so the output
+        // is the same however many threads there are
+        let rows: Vec<Vec<Cell>> = (0..dims.height)
+            .into_par_iter()
+            .step_by(self.subpixels().1 as usize)
+            .map(|i| horiz_spacer.clone()
+                .chain(
+                    (0..dims.width)
+                        .step_by(self.subpixels().0 as usize)
+                        .map(|j| self.render_pixel(&scaled_img, (j, i), options.color_fit, &colors))
+                )
+                .chain(horiz_spacer.clone())
+                .collect()
             )
+            .collect();
+
+        let cells = iter::repeat_n(vert_spacer.clone(), gap_y as usize)
+            .chain(rows)
             .chain(
-                iter::repeat(vert_spacer)
-                    .take(gap_y as usize)
+                iter::repeat_n(vert_spacer, gap_y as usize)
             )
-            .collect()
+            .collect();
+
+        Frame { prelude: colors.prelude(), cells, graphics: Vec::new() }
+    }
+
+    /// The color that dithering reduces `color` to. BBS output is only black and white, Sixel
+    /// images use the xterm color cube, and everything else is dithered to the colors the
+    /// terminal is going to show.
+    fn dither_quantize(&self, color: Rgb<u8>, colors: &ColorMap) -> Rgb<u8> {
+        match self {
+            Self::BBS => {
+                let black = Rgb::from([0u8, 0u8, 0u8]);
+                let white = Rgb::from([255u8, 255u8, 255u8]);
+                if is_closer_to_fg(&color, &white, &black) { white } else { black }
+            }
+            Self::Sixel => quantize_to_cube(color),
+            _ => colors.quantize(color),
+        }
     }
 
-    fn render_pixel(&self, img: &RgbImage, loc: (u32, u32)) -> String {
+    /// Roughly the distance between neighbouring colors from `dither_quantize`.
+    fn dither_spread(&self, colors: &ColorMap) -> f32 {
+        match self {
+            Self::BBS => 255.0,
+            Self::Sixel => 51.0,
+            _ => colors.spread(),
+        }
+    }
+
+    fn render_pixel(&self, img: &RgbImage, loc: (u32, u32), color_fit: ColorFit, colors: &ColorMap) -> Cell {
         match self {
             Self::PixelChar => {
                 let px = img.get_pixel(loc.0, loc.1);
-                "\u{2588}"
-                    .truecolor(px[0], px[1], px[2])
-                    .to_string()
+                colors.paint('\u{2588}', px, None)
             },
             Self::HalfChar => {
                 let px = img.get_pixel(loc.0, loc.1);
                 let px2 = img.get_pixel(loc.0, loc.1 + 1);
-                "\u{2580}"
-                    .truecolor(px[0], px[1], px[2])
-                    .on_truecolor(px2[0], px2[1], px2[2])
-                    .to_string()
+                colors.paint('\u{2580}', px, Some(px2))
             },
             Self::Quarters => {
-                let px = img.get_pixel(loc.0, loc.1);
-                let px2 = img.get_pixel(loc.0 + 1, loc.1);
-                let px3 = img.get_pixel(loc.0, loc.1 + 1);
-                let px4 = img.get_pixel(loc.0 + 1, loc.1 + 1);
-                let extremes = get_extreme_colors(&[px, px2, px3, px4]);
-                get_quarters_char((
-                    is_closer_to_fg(px, extremes.0, extremes.1),
-                    is_closer_to_fg(px2, extremes.0, extremes.1),
-                    is_closer_to_fg(px3, extremes.0, extremes.1),
-                    is_closer_to_fg(px4, extremes.0, extremes.1)
-                ))
-                    .truecolor(extremes.0[0], extremes.0[1], extremes.0[2])
-                    .on_truecolor(extremes.1[0], extremes.1[1], extremes.1[2])
-                    .to_string()
+                let pixels = [
+                    img.get_pixel(loc.0, loc.1),
+                    img.get_pixel(loc.0 + 1, loc.1),
+                    img.get_pixel(loc.0, loc.1 + 1),
+                    img.get_pixel(loc.0 + 1, loc.1 + 1),
+                ];
+                let (subpixels, fg, bg) = split_colors(pixels, color_fit);
+                colors.paint(
+                    get_quarters_char((subpixels[0], subpixels[1], subpixels[2], subpixels[3])),
+                    &fg,
+                    Some(&bg),
+                )
+            },
+            Self::Sextants => {
+                let pixels = [
+                    img.get_pixel(loc.0, loc.1),
+                    img.get_pixel(loc.0 + 1, loc.1),
+                    img.get_pixel(loc.0, loc.1 + 1),
+                    img.get_pixel(loc.0 + 1, loc.1 + 1),
+                    img.get_pixel(loc.0, loc.1 + 2),
+                    img.get_pixel(loc.0 + 1, loc.1 + 2),
+                ];
+                let (subpixels, fg, bg) = split_colors(pixels, color_fit);
+                colors.paint(get_sextant_char(subpixels), &fg, Some(&bg))
             },
             Self::Braille => {
                 let pixels = [
@@ -180,16 +301,22 @@ impl Renderer {
                     img.get_pixel(loc.0, loc.1 + 3),
                     img.get_pixel(loc.0 + 1, loc.1 + 3),
                 ];
-                let extremes = get_extreme_colors(&pixels);
-                let mut subpixels = [false; 8];
-                for i in 0..8 {
-                    subpixels[i] = is_closer_to_fg(pixels[i], extremes.0, extremes.1);
-                }
-                get_braille_char(subpixels)
-                    .to_string()
-                    .truecolor(extremes.0[0], extremes.0[1], extremes.0[2])
-                    .on_truecolor(extremes.1[0], extremes.1[1], extremes.1[2])
-                    .to_string()
+                let (subpixels, fg, bg) = split_colors(pixels, color_fit);
+                colors.paint(get_braille_char(subpixels), &fg, Some(&bg))
+            },
+            Self::Octants => {
+                let pixels = [
+                    img.get_pixel(loc.0, loc.1),
+                    img.get_pixel(loc.0 + 1, loc.1),
+                    img.get_pixel(loc.0, loc.1 + 1),
+                    img.get_pixel(loc.0 + 1, loc.1 + 1),
+                    img.get_pixel(loc.0, loc.1 + 2),
+                    img.get_pixel(loc.0 + 1, loc.1 + 2),
+                    img.get_pixel(loc.0, loc.1 + 3),
+                    img.get_pixel(loc.0 + 1, loc.1 + 3),
+                ];
+                let (subpixels, fg, bg) = split_colors(pixels, color_fit);
+                colors.paint(get_octant_char(subpixels), &fg, Some(&bg))
             },
             Self::BBS => {
                 let px = img.get_pixel(loc.0, loc.1);
@@ -198,14 +325,72 @@ impl Renderer {
                 let px4 = img.get_pixel(loc.0 + 1, loc.1 + 1);
                 let black = Rgb::from([0u8, 0u8, 0u8]);
                 let white = Rgb::from([255u8, 255u8, 255u8]);
-                get_quarters_char((
+                Cell::plain(get_quarters_char((
                     is_closer_to_fg(px, &white, &black),
                     is_closer_to_fg(px2, &white, &black),
                     is_closer_to_fg(px3, &white, &black),
                     is_closer_to_fg(px4, &white, &black)
-                ))
-                    .to_string()
+                )))
+            }
+            // Drawn as a whole image in render_player
+            Self::Sixel | Self::Kitty => Cell::BLANK,
+        }
+    }
+}
+
+/// Splits the subpixels of a cell into two groups, returning which ones belong to the foreground
+/// along with the foreground and background colors.
+fn split_colors<const N: usize>(pixels: [&Rgb<u8>; N], color_fit: ColorFit) -> ([bool; N], Rgb<u8>, Rgb<u8>) {
+    let extremes = get_extreme_colors(&pixels);
+
+    match color_fit {
+        ColorFit::Luma => {
+            let mut subpixels = [false; N];
+            for i in 0..N {
+                subpixels[i] = is_closer_to_fg(pixels[i], extremes.0, extremes.1);
+            }
+            (subpixels, *extremes.0, *extremes.1)
+        }
+        ColorFit::Oklab => {
+            let lab = pixels.map(Oklab::from_rgb);
+            // Seeding with the extremes keeps the brighter cluster as the foreground
+            let mut centers = [Oklab::from_rgb(extremes.0), Oklab::from_rgb(extremes.1)];
+            let mut subpixels = [true; N];
+
+            for _ in 0..4 {
+                for i in 0..N {
+                    subpixels[i] = lab[i].distance_squared(&centers[0]) <= lab[i].distance_squared(&centers[1]);
+                }
+
+                let mut sums = [Oklab::default(); 2];
+                let mut counts = [0; 2];
+                for i in 0..N {
+                    let cluster = if subpixels[i] { 0 } else { 1 };
+                    sums[cluster].l += lab[i].l;
+                    sums[cluster].a += lab[i].a;
+                    sums[cluster].b += lab[i].b;
+                    counts[cluster] += 1;
+                }
+
+                let mut changed = false;
+                for cluster in 0..2 {
+                    if counts[cluster] == 0 {
+                        continue;
+                    }
+                    let n = counts[cluster] as f32;
+                    let mean = Oklab { l: sums[cluster].l / n, a: sums[cluster].a / n, b: sums[cluster].b / n };
+                    changed |= mean != centers[cluster];
+                    centers[cluster] = mean;
+                }
+                if !changed {
+                    break;
+                }
+            }
+
+            if subpixels.iter().all(|s| *s) {
+                centers[1] = centers[0];
             }
+            (subpixels, centers[0].to_rgb(), centers[1].to_rgb())
         }
     }
 }
@@ -234,24 +419,24 @@ fn is_closer_to_fg(color: &Rgb<u8>, fg: &Rgb<u8>, bg: &Rgb<u8>) -> bool {
     (brightness - calc_brightness(fg)).abs() <= (brightness - calc_brightness(bg)).abs()
 }
 
-fn get_quarters_char(subpixels: (bool, bool, bool, bool)) -> &'static str {
+pub(crate) fn get_quarters_char(subpixels: (bool, bool, bool, bool)) -> char {
     match subpixels {  // tl, tr, bl, br
-        (false, false, false, false) => " ",
-        (false, false, false, true ) => "▗",
-        (false, false, true , false) => "▖",
-        (false, false, true , true ) => "▄",
-        (false, true , false, false) => "▝",
-        (false, true , false, true ) => "▐",
-        (false, true , true , false) => "▞",
-        (false, true , true , true ) => "▟",
-        (true , false, false, false) => "▘",
-        (true , false, false, true ) => "▚",
-        (true , false, true , false) => "▌",
-        (true , false, true , true ) => "▙",
-        (true , true , false, false) => "▀",
-        (true , true , false, true ) => "▜",
-        (true , true , true , false) => "▛",
-        (true , true , true , true ) => "█",
+        (false, false, false, false) => ' ',
+        (false, false, false, true ) => '▗',
+        (false, false, true , false) => '▖',
+        (false, false, true , true ) => '▄',
+        (false, true , false, false) => '▝',
+        (false, true , false, true ) => '▐',
+        (false, true , true , false) => '▞',
+        (false, true , true , true ) => '▟',
+        (true , false, false, false) => '▘',
+        (true , false, false, true ) => '▚',
+        (true , false, true , false) => '▌',
+        (true , false, true , true ) => '▙',
+        (true , true , false, false) => '▀',
+        (true , true , false, true ) => '▜',
+        (true , true , true , false) => '▛',
+        (true , true , true , true ) => '█',
     }
 }
 
@@ -263,4 +448,91 @@ fn get_braille_char(subpixels: [bool; 8]) -> char {
         }
     }
     char::from_u32(c).unwrap()
-}
\ No newline at end of file
+}
+
+fn subpixel_mask(subpixels: &[bool]) -> u32 {
+    subpixels
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| **s)
+        .map(|(i, _)| 1 << i)
+        .sum()
+}
+
+/// Subpixels are in row-major order (tl, tr, ml, mr, bl, br). Patterns that already exist as
+/// half blocks are left out of the U+1FB00 block, so they are shifted around.
+fn get_sextant_char(subpixels: [bool; 6]) -> char {
+    match subpixel_mask(&subpixels) {
+        0 => ' ',
+        21 => '▌',
+        42 => '▐',
+        63 => '█',
+        n @ 1..=20 => char::from_u32(0x1FB00 + n - 1).unwrap(),
+        n @ 22..=41 => char::from_u32(0x1FB00 + n - 2).unwrap(),
+        n => char::from_u32(0x1FB00 + n - 3).unwrap(),
+    }
+}
+
+/// Octant patterns that are already encoded elsewhere (quadrants, eighth blocks, etc.) and so
+/// are skipped by the Unicode 16 block octants at U+1CD00.
+const OCTANT_EXCEPTIONS: [(u32, char); 26] = [
+    (0, ' '), (1, '\u{1CEA8}'), (2, '\u{1CEAB}'), (3, '\u{1FB82}'), (5, '▘'), (10, '▝'),
+    (15, '▀'), (20, '\u{1FBE6}'), (40, '\u{1FBE7}'), (63, '\u{1FB85}'), (64, '\u{1CEA3}'),
+    (80, '▖'), (85, '▌'), (90, '▞'), (95, '▛'), (128, '\u{1CEA0}'), (160, '▗'), (165, '▚'),
+    (170, '▐'), (175, '▜'), (192, '▂'), (240, '▄'), (245, '▙'), (250, '▟'), (252, '▆'),
+    (255, '█'),
+];
+
+/// Subpixels are in row-major order, two per row.
+fn get_octant_char(subpixels: [bool; 8]) -> char {
+    let mask = subpixel_mask(&subpixels);
+    let mut skipped = 0;
+    for (exception, c) in OCTANT_EXCEPTIONS {
+        if exception == mask {
+            return c;
+        } else if exception < mask {
+            skipped += 1;
+        }
+    }
+    char::from_u32(0x1CD00 + mask - skipped).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::{Grid, Screen};
+
+    /// Renders a fixed, busy image the way the player would, on a pool of `threads` threads.
+    fn render(renderer: Renderer, color_fit: ColorFit, threads: usize) -> (Grid, String) {
+        let img = RgbImage::from_fn(320, 180, |x, y| {
+            Rgb([(x * 7 + y * 3) as u8, (x * y % 251) as u8, ((x ^ y) * 5) as u8])
+        });
+        let options = RenderOptions {
+            char_height: 2.2,
+            color_fit,
+            dither: Dither::Bayer4,
+            color_depth: ColorDepth::Ansi256,
+            adaptive_palette: false,
+        };
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let area = Area { width: 120, height: 40 };
+        let frame = pool.install(|| renderer.render_player(&img, (320, 180), area, options));
+        let cells = frame.cells.clone();
+        (cells, Screen::new().draw(frame))
+    }
+
+    #[test]
+    fn threads_dont_change_output() {
+        for renderer in [Renderer::Braille, Renderer::Quarters] {
+            for color_fit in [ColorFit::Luma, ColorFit::Oklab] {
+                let (serial, serial_output) = render(renderer, color_fit, 1);
+                assert_eq!(serial.len(), 40);
+                for threads in [2, 3, 8] {
+                    let (parallel, parallel_output) = render(renderer, color_fit, threads);
+                    assert_eq!(parallel, serial);
+                    assert_eq!(parallel_output, serial_output);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::fmt::Write;
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::palette::TermColor;
+
+/// Stands in for the second column of a double-width character.
+pub(crate) const WIDE_TAIL: char = '\0';
+
+/// One character on the screen, along with how it's drawn.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Cell {
+    pub(crate) ch: char,
+    pub(crate) fg: Option<TermColor>,
+    pub(crate) bg: Option<TermColor>,
+    pub(crate) bold: bool,
+    pub(crate) reverse: bool,
+}
+
+impl Cell {
+    pub(crate) const BLANK: Self = Self::plain(' ');
+
+    pub(crate) const fn plain(ch: char) -> Self {
+        Self {
+            ch,
+            fg: None,
+            bg: None,
+            bold: false,
+            reverse: false,
+        }
+    }
+
+    pub(crate) fn bold(self) -> Self {
+        Self { bold: true, ..self }
+    }
+
+    /// The SGR parameters that switch the terminal from drawing like `current` to drawing like
+    /// this cell, or `None` if nothing has to change.
+    fn sgr_from(&self, current: &Cell) -> Option<String> {
+        let mut codes = Vec::new();
+
+        // Attributes can't be turned off one at a time portably, so start over from a reset
+        let reset = (current.bold && !self.bold)
+            || (current.reverse && !self.reverse)
+            || (current.fg.is_some() && self.fg.is_none())
+            || (current.bg.is_some() && self.bg.is_none());
+        let current = if reset {
+            codes.push("0".to_string());
+            &Cell::BLANK
+        } else {
+            current
+        };
+
+        if self.bold && !current.bold {
+            codes.push("1".to_string());
+        }
+        if self.reverse && !current.reverse {
+            codes.push("7".to_string());
+        }
+        if let Some(fg) = self.fg.filter(|fg| current.fg != Some(*fg)) {
+            codes.push(fg.fg_sgr());
+        }
+        if let Some(bg) = self.bg.filter(|bg| current.bg != Some(*bg)) {
+            codes.push(bg.bg_sgr());
+        }
+
+        (!codes.is_empty()).then(|| codes.join(";"))
+    }
+
+    fn is_styled(&self) -> bool {
+        self.bold || self.reverse || self.fg.is_some() || self.bg.is_some()
+    }
+}
+
+pub(crate) type Grid = Vec<Vec<Cell>>;
+
+/// A rendered frame, as cells plus anything that has to be sent to the terminal first.
+#[derive(Clone, Default)]
+pub(crate) struct Frame {
+    /// Escape sequences that don't take up any cells, like palette changes.
+    pub(crate) prelude: String,
+    pub(crate) cells: Grid,
+    pub(crate) graphics: Vec<Graphic>,
+}
+
+impl Frame {
+    /// Cuts off whatever doesn't fit in a terminal `size` columns and rows, which would otherwise
+    /// wrap and push everything else out of place.
+    pub(crate) fn crop(&mut self, size: (u16, u16)) {
+        let (width, height) = (size.0 as usize, size.1 as usize);
+        self.cells.truncate(height);
+        for row in &mut self.cells {
+            if row.len() > width {
+                row.truncate(width);
+                // Don't leave half of a wide character at the edge
+                if row.last().is_some_and(|cell| cell.ch.width() == Some(2)) {
+                    *row.last_mut().unwrap() = Cell::BLANK;
+                }
+            }
+        }
+        self.graphics.retain(|graphic| (graphic.x as usize) < width && (graphic.y as usize) < height);
+    }
+}
+
+/// An image drawn on top of the cells, positioned in cells from the top left of the frame.
+#[derive(Clone)]
+pub(crate) struct Graphic {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) data: String,
+}
+
+/// Turns plain text into cells, taking up two cells for wide characters and dropping anything
+/// that has no width.
+pub(crate) fn text_cells(text: &str) -> Vec<Cell> {
+    let mut cells = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match c.width() {
+            Some(1) => cells.push(Cell::plain(c)),
+            Some(2) => {
+                cells.push(Cell::plain(c));
+                cells.push(Cell::plain(WIDE_TAIL));
+            }
+            _ => {}
+        }
+    }
+    cells
+}
+
+/// Draws text written by `Screen::draw` onto `frame`, the way a terminal `width` cells wide
+/// would. Palette changes go into the prelude, and images are placed wherever the cursor was last
+/// moved to. Older recordings have no cursor movement at all, and just wrap from row to row.
+pub(crate) fn apply_ansi(frame: &mut Frame, text: &str, width: usize) {
+    let mut cursor = Cursor { cells: &mut frame.cells, width, x: 0, y: 0 };
+    let mut anchor = (0, 0);
+    let mut style = Cell::BLANK;
+    let mut last = None;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            match chars.next() {
+                Some('[') => {
+                    let mut params = String::new();
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            let mut numbers = params.split(';').map(|p| p.parse::<usize>().unwrap_or(0));
+                            match c {
+                                'm' => apply_sgr(&mut style, &params),
+                                'H' => {
+                                    cursor.y = numbers.next().unwrap_or(1).max(1) - 1;
+                                    cursor.x = numbers.next().unwrap_or(1).max(1) - 1;
+                                    anchor = (cursor.x as u32, cursor.y as u32);
+                                }
+                                'K' => cursor.erase_line(),
+                                // REP, which repeats the last character
+                                'b' => {
+                                    if let Some(last) = last {
+                                        for _ in 0..numbers.next().unwrap_or(1).max(1) {
+                                            cursor.put(last);
+                                        }
+                                    }
+                                }
+                                _ => {}
+                            }
+                            break;
+                        }
+                        params.push(c);
+                    }
+                }
+                // OSC, DCS and APC all run until the string terminator
+                Some(kind @ (']' | 'P' | '_')) => {
+                    let mut sequence = format!("\x1b{}", kind);
+                    while let Some(c) = chars.next() {
+                        sequence.push(c);
+                        if c == '\x07' || (c == '\x1b' && chars.peek() == Some(&'\\')) {
+                            if c == '\x1b' {
+                                sequence.push(chars.next().unwrap());
+                            }
+                            break;
+                        }
+                    }
+                    if kind == ']' {
+                        frame.prelude.push_str(&sequence);
+                    } else {
+                        frame.graphics.push(Graphic { x: anchor.0, y: anchor.1, data: sequence });
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let cell = Cell { ch: c, ..style };
+        if cursor.put(cell) {
+            last = Some(cell);
+        }
+    }
+}
+
+/// Where `apply_ansi` is writing to, in the cells of a frame.
+struct Cursor<'a> {
+    cells: &'a mut Grid,
+    width: usize,
+    x: usize,
+    y: usize,
+}
+
+impl Cursor<'_> {
+    /// Writes a character and moves past it, taking up two cells for wide characters. Returns
+    /// whether anything was written, since characters with no width are dropped.
+    fn put(&mut self, cell: Cell) -> bool {
+        match cell.ch.width() {
+            Some(1) => self.write(cell),
+            Some(2) => {
+                self.write(cell);
+                self.write(Cell { ch: WIDE_TAIL, ..cell });
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn write(&mut self, cell: Cell) {
+        if self.cells.len() <= self.y {
+            self.cells.resize(self.y + 1, Vec::with_capacity(self.width));
+        }
+        let row = &mut self.cells[self.y];
+        if row.len() <= self.x {
+            row.resize(self.x + 1, Cell::BLANK);
+        }
+        row[self.x] = cell;
+
+        self.x += 1;
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+    }
+
+    /// Clears the rest of the row, like `Screen::draw` does when a row gets shorter.
+    fn erase_line(&mut self) {
+        if let Some(row) = self.cells.get_mut(self.y) {
+            row.truncate(self.x);
+        }
+    }
+}
+
+/// Applies the parameters of an SGR sequence to the style in `cell`.
+fn apply_sgr(cell: &mut Cell, params: &str) {
+    let params = params.split(';').map(|p| p.parse::<u8>().unwrap_or(0)).collect::<Vec<u8>>();
+    let mut params = params.iter().copied();
+    while let Some(param) = params.next() {
+        match param {
+            0 => *cell = Cell::BLANK,
+            1 => cell.bold = true,
+            7 => cell.reverse = true,
+            22 => cell.bold = false,
+            27 => cell.reverse = false,
+            30..=37 => cell.fg = Some(TermColor::Indexed(param - 30)),
+            90..=97 => cell.fg = Some(TermColor::Indexed(param - 90 + 8)),
+            39 => cell.fg = None,
+            40..=47 => cell.bg = Some(TermColor::Indexed(param - 40)),
+            100..=107 => cell.bg = Some(TermColor::Indexed(param - 100 + 8)),
+            49 => cell.bg = None,
+            38 | 48 => {
+                let color = match params.next() {
+                    Some(5) => params.next().map(TermColor::Indexed),
+                    Some(2) => match (params.next(), params.next(), params.next()) {
+                        (Some(r), Some(g), Some(b)) => Some(TermColor::Rgb([r, g, b])),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if param == 38 {
+                    cell.fg = color;
+                } else {
+                    cell.bg = color;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Writes out a run of cells, only sending escape sequences where the style changes. The
+/// terminal is left with the default style afterwards. With `repeat`, runs of the same cell are
+/// shortened with REP where that's smaller.
+pub(crate) fn encode_cells(cells: &[Cell], repeat: bool, out: &mut String) {
+    let mut current = Cell::BLANK;
+    let mut i = 0;
+    while i < cells.len() {
+        let cell = &cells[i];
+        i += 1;
+        if cell.ch == WIDE_TAIL {
+            continue;
+        }
+        if let Some(sgr) = cell.sgr_from(&current) {
+            write!(out, "\x1b[{}m", sgr).unwrap();
+            current = *cell;
+        }
+        out.push(cell.ch);
+
+        if repeat {
+            let run = cells[i..].iter().take_while(|other| *other == cell).count();
+            let rep = format!("\x1b[{}b", run);
+            if rep.len() < run * cell.ch.len_utf8() {
+                out.push_str(&rep);
+                i += run;
+            }
+        }
+    }
+    if current.is_styled() {
+        out.push_str("\x1b[0m");
+    }
+}
+
+/// Keeps a copy of what's on the terminal, so that each frame only has to send the cells that
+/// changed since the last one.
+pub(crate) struct Screen {
+    front: Grid,
+    repeat: bool,
+}
+
+impl Screen {
+    pub(crate) fn new() -> Self {
+        Self { front: Vec::new(), repeat: false }
+    }
+
+    /// A screen that shortens runs of the same cell with REP. Not every terminal has it, so this
+    /// is only used for output that this player reads back, like recordings.
+    pub(crate) fn repeating() -> Self {
+        Self { front: Vec::new(), repeat: true }
+    }
+
+    /// Forces the next frame to be drawn in full.
+    pub(crate) fn invalidate(&mut self) {
+        self.front.clear();
+    }
+
+    /// Returns what has to be written to the terminal to go from the last frame to `frame`.
+    pub(crate) fn draw(&mut self, frame: Frame) -> String {
+        let mut out = frame.prelude;
+
+        for (y, row) in frame.cells.iter().enumerate() {
+            let old = self.front.get(y).map(Vec::as_slice).unwrap_or(&[]);
+
+            let mut x = 0;
+            while x < row.len() {
+                if old.get(x) == Some(&row[x]) {
+                    x += 1;
+                    continue;
+                }
+
+                // The tail of a wide character can only be redrawn with its head
+                let start = if row[x].ch == WIDE_TAIL && x > 0 { x - 1 } else { x };
+                let mut end = x + 1;
+                loop {
+                    while end < row.len() && old.get(end) != Some(&row[end]) {
+                        end += 1;
+                    }
+                    // Rewriting a few unchanged cells is cheaper than moving the cursor past them
+                    match (end..row.len().min(end + 4)).find(|&i| old.get(i) != Some(&row[i])) {
+                        Some(next) => end = next,
+                        None => break,
+                    }
+                }
+                if end < row.len() && row[end].ch == WIDE_TAIL {
+                    end += 1;
+                }
+
+                write!(out, "\x1b[{};{}H", y + 1, start + 1).unwrap();
+                encode_cells(&row[start..end], self.repeat, &mut out);
+                x = end;
+            }
+
+            if row.len() < old.len() {
+                write!(out, "\x1b[{};{}H\x1b[K", y + 1, row.len() + 1).unwrap();
+            }
+        }
+
+        for y in frame.cells.len()..self.front.len() {
+            write!(out, "\x1b[{};1H\x1b[K", y + 1).unwrap();
+        }
+
+        // The cells underneath don't change, so images are sent again on every frame
+        for graphic in &frame.graphics {
+            write!(out, "\x1b[{};{}H{}", graphic.y + 1, graphic.x + 1, graphic.data).unwrap();
+        }
+
+        self.front = frame.cells;
+        out
+    }
+}
@@ -1,132 +1,441 @@
+use std::collections::VecDeque;
 use std::error::Error;
-use std::io::{Cursor, Read};
-use std::process::{Command, Stdio};
+use std::io::{self, PipeReader, Read};
+use std::process::{ChildStdout, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use image::imageops::FilterType;
 use image::{Rgb, RgbImage};
 
-use crate::ImageReader;
+use crate::audio::{Audio, AudioOutput, Clock, CHANNELS, SAMPLE_RATE};
+
+/// The size frames are decoded at when ffprobe can't tell what the video's is.
+const FALLBACK_SIZE: (u32, u32) = (640, 360);
+
+/// How many decoded frames are kept ready ahead of the one being shown.
+pub(crate) const QUEUE_LENGTH: usize = 16;
+
+/// How long a new frame size has to stay the same before ffmpeg is restarted for it, so dragging
+/// the window around doesn't restart it on every frame.
+const RESIZE_DELAY: Duration = Duration::from_millis(250);
 
 pub(crate) struct Source {
     source_stream: SourceStream,
+    /// Reads frames from the video decoder, if there is one.
+    decoder: Option<Decoder>,
+    path: Option<String>,
     paused: bool,
     pub(crate) finished: bool,
     framerate: u32,
+    to_file: bool,
+    /// The timestamp of the frame after the one that was shown last.
+    position: Duration,
+    /// Paces the video. It follows the audio if there is any, and wall time if not.
+    clock: Arc<Clock>,
+    frames_dropped: u32,
+    frames_repeated: u32,
+    probe: Probe,
+    /// The size ffmpeg scales frames to. This starts out as the size of the video, and is then
+    /// set to what the renderer needs so the scaling doesn't have to happen on every frame here.
+    size: (u32, u32),
+    /// Whether a frame size has been asked for yet. The first one is used right away, since
+    /// nothing has been shown at the old one.
+    size_set: bool,
+    /// A size that's waiting out `RESIZE_DELAY`, and when it was first asked for.
+    pending_size: Option<((u32, u32), Instant)>,
+    /// Frames are read straight into this buffer, so it's also what's shown when there's no new one.
     last_frame: RgbImage,
+    audio_output: Option<AudioOutput>,
+    audio: Option<Audio>,
 }
 
 impl Source {
-    pub(crate) fn new(path: Option<&str>, framerate: u32, to_file: bool) -> Result<Self, Box<dyn Error>> {
-        let framerate_str = format!("fps={}", framerate);
+    pub(crate) fn new(
+        path: Option<&str>,
+        framerate: u32,
+        to_file: bool,
+        start: Duration,
+        audio_output: Option<&AudioOutput>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let probe = path.map_or_else(Probe::default, Probe::new);
+        let size = probe.size;
+        Self::spawn(path, framerate, to_file, start, probe, size, audio_output)
+    }
 
-        let direct_stderr = || if to_file {
-            Stdio::inherit()
-        } else {
-            Stdio::null()
+    fn spawn(
+        path: Option<&str>,
+        framerate: u32,
+        to_file: bool,
+        start: Duration,
+        probe: Probe,
+        size: (u32, u32),
+        audio_output: Option<&AudioOutput>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (source_stream, outputs) =
+            SourceStream::spawn(path, framerate, to_file, start, size, audio_output.is_some() && probe.audio)?;
+        let (decoder, audio) = match outputs {
+            Some(Outputs { video, audio }) => (
+                Some(Decoder::spawn(video, start, framerate, size)),
+                audio.zip(audio_output).map(|(stream, output)| output.play(stream, start)),
+            ),
+            None => (None, None),
         };
+        let clock = audio.as_ref().map_or_else(|| Arc::new(Clock::without_audio(start)), Audio::clock);
 
-        if let Some(path) = path {
-            if path.contains("http") {
-                let mut ytdl_process = Command::new("yt-dlp")
-                    .args(&["-o", "-", path])
-                    .stdout(Stdio::piped())
-                    .stderr(direct_stderr())
-                    .spawn()?;
-
-                let ffmpeg_args = if to_file { vec![
-                    "-i", "-",
-                    "-f", "image2pipe", "-c:v", "bmp", "-vf", &framerate_str, "-",
-                ] } else { vec![
-                        "-re", "-i", "-",
-                        "-f", "image2pipe", "-c:v", "bmp", "-vf", &framerate_str, "-",
-                        "-f", "pulse", "\"unicode_player\"",
-                ] };
-
-                let mut ffmpeg_process = Command::new("ffmpeg")
-                    .args(&ffmpeg_args)
-                    .stdin(Stdio::from(ytdl_process.stdout.take().ok_or("Couldn't get yt-dlp stdout")?))
-                    .stdout(Stdio::piped())
-                    .stderr(direct_stderr())
-                    .spawn()?;
-
-                let stream = ffmpeg_process.stdout.take().ok_or("Couldn't get ffmpeg stdout")?;
-
-                Ok(Self {
-                    source_stream: SourceStream::YouTube { ytdl: ytdl_process, ffmpeg: ffmpeg_process, stream },
-                    paused: false,
-                    finished: false,
-                    framerate,
-                    last_frame: blank_frame(),
-                })
-            } else {
-                let ffmpeg_args = if to_file { vec![
-                    "-i", path,
-                    "-f", "image2pipe", "-c:v", "bmp", "-vf", &framerate_str, "-",
-                ] } else { vec![
-                    "-re", "-i", path,
-                    "-f", "image2pipe", "-c:v", "bmp", "-vf", &framerate_str, "-",
-                    "-f", "pulse", "\"unicode_player\"",
-                ] };
-
-                let mut ffmpeg_process = Command::new("ffmpeg")
-                    .args(&ffmpeg_args)
-                    .stdout(Stdio::piped())
-                    // .stderr(direct_stderr())
-                    .spawn()?;
-
-                let stream = ffmpeg_process.stdout.take().ok_or("Couldn't get ffmpeg stdout")?;
-
-                Ok(Self {
-                    source_stream: SourceStream::File { ffmpeg: ffmpeg_process, stream },
-                    paused: false,
-                    finished: false,
-                    framerate,
-                    last_frame: blank_frame(),
-                })
+        Ok(Self {
+            source_stream,
+            decoder,
+            path: path.map(str::to_string),
+            paused: false,
+            finished: false,
+            framerate,
+            to_file,
+            position: start,
+            clock,
+            frames_dropped: 0,
+            frames_repeated: 0,
+            probe,
+            size,
+            size_set: false,
+            pending_size: None,
+            last_frame: if path.is_some() { RgbImage::new(size.0, size.1) } else { blank_frame() },
+            audio_output: audio_output.cloned(),
+            audio,
+        })
+    }
+
+    /// Stops decoding and playing audio. This happens on drop anyway, but the audio sink has to
+    /// be closed before another source opens its own.
+    pub(crate) fn stop(&mut self) {
+        if let Some(decoder) = &self.decoder {
+            decoder.stop();
+        }
+        // Killing ffmpeg ends the audio stream too, which lets the audio thread finish
+        self.source_stream.stop();
+        if let Some(audio) = &mut self.audio {
+            audio.join();
+        }
+    }
+
+    /// Restarts the decoder `offset` seconds away from the current position, clamped to the
+    /// length of the source if it is known.
+    pub(crate) fn seek(&mut self, offset: f32) -> Result<(), Box<dyn Error>> {
+        if matches!(self.source_stream, SourceStream::Blank) {
+            return Ok(());
+        }
+
+        let mut target = (self.position().as_secs_f32() + offset).max(0.0);
+        if let Some(duration) = self.probe.duration {
+            target = target.min(duration.as_secs_f32());
+        }
+
+        // ffmpeg is being restarted anyway, so a size that's still waiting can be used now
+        let size = self.pending_size.take().map_or(self.size, |(size, _)| size);
+        self.restart(Duration::from_secs_f32(target), size)
+    }
+
+    /// Starts decoding again from `start` with frames scaled to `size`, keeping the last frame
+    /// on screen and staying paused if it was. The dropped and repeated frames keep counting.
+    fn restart(&mut self, start: Duration, size: (u32, u32)) -> Result<(), Box<dyn Error>> {
+        let paused = self.paused;
+        let size_set = self.size_set;
+        let (frames_dropped, frames_repeated) = (self.frames_dropped, self.frames_repeated);
+        let last_frame = std::mem::replace(&mut self.last_frame, blank_frame());
+        self.stop();
+        *self = Self::spawn(
+            self.path.as_deref(),
+            self.framerate,
+            self.to_file,
+            start,
+            self.probe.clone(),
+            size,
+            self.audio_output.as_ref(),
+        )?;
+        if paused {
+            self.toggle_pause();
+        }
+        self.size_set = size_set;
+        self.frames_dropped = frames_dropped;
+        self.frames_repeated = frames_repeated;
+        self.last_frame = last_frame;
+        Ok(())
+    }
+
+    /// The timestamp of the next frame.
+    pub(crate) fn position(&self) -> Duration {
+        self.position
+    }
+
+    pub(crate) fn duration(&self) -> Option<Duration> {
+        self.probe.duration
+    }
+
+    /// The title from the file's metadata or the video page, if it has one.
+    pub(crate) fn title(&self) -> Option<&str> {
+        self.probe.title.as_deref()
+    }
+
+    /// The size of the video itself, before it's scaled for the renderer.
+    pub(crate) fn video_size(&self) -> (u32, u32) {
+        match self.source_stream {
+            SourceStream::Blank => self.last_frame.dimensions(),
+            _ => self.probe.size,
+        }
+    }
+
+    /// Has ffmpeg scale frames to `size` from now on. ffmpeg is restarted where it left off if the
+    /// size changed, which restarts the audio with it. After the first call, a new size only
+    /// takes effect once it's been asked for over `RESIZE_DELAY`, and until then frames come at
+    /// the old size.
+    pub(crate) fn set_frame_size(&mut self, size: (u32, u32)) -> Result<(), Box<dyn Error>> {
+        if size.0 == 0 || size.1 == 0 || self.finished {
+            return Ok(());
+        }
+        if matches!(self.source_stream, SourceStream::Blank) {
+            return Ok(());
+        }
+        let first = !std::mem::replace(&mut self.size_set, true);
+        if size == self.size {
+            self.pending_size = None;
+            return Ok(());
+        }
+        // Exporting sets the size once before any frames, and there's nobody waiting to see them
+        if !first && !self.to_file {
+            match self.pending_size {
+                Some((pending, since)) if pending == size && since.elapsed() >= RESIZE_DELAY => {}
+                Some((pending, _)) if pending == size => return Ok(()),
+                _ => {
+                    self.pending_size = Some((size, Instant::now()));
+                    return Ok(());
+                }
             }
-        } else {
-            Ok(Self {
-                source_stream: SourceStream::Blank,
-                paused: false,
-                finished: false,
-                framerate,
-                last_frame: blank_frame()
-            })
         }
+        self.pending_size = None;
+
+        self.restart(self.position, size)?;
+        // Keep showing the last frame until the next one arrives at the new size
+        self.last_frame = image::imageops::resize(&self.last_frame, size.0, size.1, FilterType::Triangle);
+        Ok(())
     }
 
+    /// How many frames were skipped because they were already late.
+    pub(crate) fn frames_dropped(&self) -> u32 {
+        self.frames_dropped
+    }
+
+    /// How many times the last frame was shown again, because the clock fell behind the video or
+    /// the decoder fell behind the clock.
+    pub(crate) fn frames_repeated(&self) -> u32 {
+        self.frames_repeated
+    }
+
+    /// How many decoded frames are waiting to be shown.
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.decoder.as_ref().map_or(0, |decoder| decoder.lock().frames.len())
+    }
+
+    /// Pausing stops the clock, so the audio stops and no more frames are read. The decoders
+    /// block once their pipes fill up, and pick up from the same frame when playback resumes.
     pub(crate) fn toggle_pause(&mut self) {
-        self.paused = !self.paused
+        self.paused = !self.paused;
+        self.clock.set_paused(self.paused);
     }
 
+    /// Returns the frame that's due at the current clock time, waiting for it if it's early.
+    /// When exporting, every frame is returned in order as soon as it's decoded instead.
     pub(crate) fn next_frame(&mut self) -> &RgbImage {
-        if self.paused || self.finished {
-            thread::sleep(Duration::from_secs_f32(1.0 / self.framerate as f32));
+        let frame_length = Duration::from_secs_f64(1.0 / self.framerate as f64);
+        // The blank source has no decoder and just paces itself
+        let decoder = match &self.decoder {
+            Some(decoder) if !self.paused && !self.finished => decoder,
+            _ => {
+                thread::sleep(frame_length);
+                return &self.last_frame;
+            }
+        };
+
+        if self.to_file {
+            let mut state = decoder.wait(None);
+            match state.frames.pop_front() {
+                Some(mut frame) => {
+                    std::mem::swap(&mut self.last_frame, &mut frame.image);
+                    decoder.recycle(&mut state, frame.image);
+                    self.position = frame.timestamp + frame_length;
+                }
+                None => self.finished = true,
+            }
             return &self.last_frame;
         }
 
-        if let Some(frame) = self.source_stream.next_frame(self.framerate) {
-            self.last_frame = frame;
-        } else {
-            self.finished = true;
+        let mut state = decoder.wait(Some(frame_length));
+        let now = self.clock.now();
+
+        // A frame is late once the one after it is due too
+        while state.frames.len() > 1 && state.frames[1].timestamp <= now {
+            let late = state.frames.pop_front().unwrap();
+            decoder.recycle(&mut state, late.image);
+            self.frames_dropped += 1;
+            self.position = late.timestamp + frame_length;
         }
 
+        let wait = match state.frames.front() {
+            None => {
+                if state.finished {
+                    self.finished = true;
+                } else {
+                    // Decoding can't keep up
+                    self.frames_repeated += 1;
+                }
+                return &self.last_frame;
+            }
+            Some(frame) if frame.timestamp > now + frame_length => {
+                // The clock is behind, like when the audio is still starting up or has stalled
+                drop(state);
+                thread::sleep(frame_length);
+                self.frames_repeated += 1;
+                return &self.last_frame;
+            }
+            Some(frame) => frame.timestamp.saturating_sub(now),
+        };
+
+        drop(state);
+        thread::sleep(wait);
+
+        // Only this thread takes frames out, so the one that was waited for is still first
+        let mut state = decoder.lock();
+        let mut frame = state.frames.pop_front().unwrap();
+        std::mem::swap(&mut self.last_frame, &mut frame.image);
+        decoder.recycle(&mut state, frame.image);
+        self.position = frame.timestamp + frame_length;
+
         &self.last_frame
     }
 }
 
+/// Reads frames from ffmpeg on its own thread, so that the render loop never waits on the pipe,
+/// and keeps up to `QUEUE_LENGTH` of them ready.
+struct Decoder {
+    shared: Arc<DecoderShared>,
+}
+
+struct DecoderShared {
+    state: Mutex<DecoderState>,
+    /// Signalled whenever a frame is added or taken, or the decoder is stopped.
+    changed: Condvar,
+}
+
+struct DecoderState {
+    frames: VecDeque<TimedFrame>,
+    /// Buffers from frames that were shown or dropped, for new frames to be read into.
+    spare: Vec<RgbImage>,
+    finished: bool,
+    stopped: bool,
+}
+
+struct TimedFrame {
+    timestamp: Duration,
+    image: RgbImage,
+}
+
+impl Decoder {
+    fn spawn(stream: ChildStdout, start: Duration, framerate: u32, size: (u32, u32)) -> Self {
+        let shared = Arc::new(DecoderShared {
+            state: Mutex::new(DecoderState {
+                frames: VecDeque::with_capacity(QUEUE_LENGTH),
+                spare: Vec::new(),
+                finished: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        thread::Builder::new()
+            .name("decoder".to_string())
+            .spawn({
+                let shared = shared.clone();
+                move || decode_thread(stream, start, framerate, size, &shared)
+            })
+            .unwrap();
+
+        Self { shared }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DecoderState> {
+        self.shared.state.lock().unwrap()
+    }
+
+    /// Waits until there's a frame in the queue or the stream has ended, for at most `timeout`.
+    fn wait(&self, timeout: Option<Duration>) -> MutexGuard<'_, DecoderState> {
+        let empty = |state: &mut DecoderState| state.frames.is_empty() && !state.finished;
+        match timeout {
+            Some(timeout) => self.shared.changed.wait_timeout_while(self.lock(), timeout, empty).unwrap().0,
+            None => self.shared.changed.wait_while(self.lock(), empty).unwrap(),
+        }
+    }
+
+    /// Frees up a buffer for the decoder thread to use again.
+    fn recycle(&self, state: &mut DecoderState, image: RgbImage) {
+        state.spare.push(image);
+        self.shared.changed.notify_all();
+    }
+
+    /// Tells the thread to stop. It notices once it's done with the frame it's reading, which
+    /// ends right away when ffmpeg is killed.
+    fn stop(&self) {
+        self.lock().stopped = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn decode_thread(mut stream: ChildStdout, start: Duration, framerate: u32, size: (u32, u32), shared: &DecoderShared) {
+    for n in 0.. {
+        let mut image = {
+            let mut state = shared
+                .changed
+                .wait_while(shared.state.lock().unwrap(), |state| {
+                    state.frames.len() >= QUEUE_LENGTH && !state.stopped
+                })
+                .unwrap();
+            if state.stopped {
+                return;
+            }
+            state.spare.pop().unwrap_or_else(|| RgbImage::new(size.0, size.1))
+        };
+
+        // The frames are packed RGB with no header, the same layout as the image buffer
+        let buffer: &mut [u8] = &mut image;
+        let read = stream.read_exact(buffer).is_ok();
+
+        let mut state = shared.state.lock().unwrap();
+        if read {
+            let timestamp = start + Duration::from_secs_f64(n as f64 / framerate as f64);
+            state.frames.push_back(TimedFrame { timestamp, image });
+        } else {
+            state.finished = true;
+        }
+        shared.changed.notify_all();
+        if !read {
+            return;
+        }
+    }
+}
+
 #[cfg(not(windows))]
 enum SourceStream {
     Blank,
     File {
         ffmpeg: std::process::Child,
-        stream: std::process::ChildStdout,
     },
     YouTube {
         ytdl: std::process::Child,
         ffmpeg: std::process::Child,
-        stream: std::process::ChildStdout,
     },
 }
 
@@ -137,70 +446,351 @@ enum SourceStream {
         ffmpeg: std::process::Child,
         // play: std::process::Child,
         // pipe: named_pipe::PipeServer,
-        stream: std::process::ChildStdout,
     },
     YouTube {
         ytdl: std::process::Child,
         ffmpeg: std::process::Child,
         // play: std::process::Child,
         // pipe: named_pipe::PipeServer,
-        stream: std::process::ChildStdout,
     },
 }
 
 impl SourceStream {
+    /// Starts decoding video from `start`, as raw frames scaled to exactly `size`. With `audio`,
+    /// the same ffmpeg decodes the sound too. The pipes are left to the decoder and audio threads.
+    fn spawn(
+        path: Option<&str>,
+        framerate: u32,
+        to_file: bool,
+        start: Duration,
+        size: (u32, u32),
+        audio: bool,
+    ) -> Result<(Self, Option<Outputs>), Box<dyn Error>> {
+        let Some(path) = path else {
+            return Ok((SourceStream::Blank, None));
+        };
+
+        let filter_str = format!("fps={},scale={}:{}", framerate, size.0, size.1);
+        let start_str = format!("{:.3}", start.as_secs_f32());
+
+        let direct_stderr = || if to_file {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        };
+
+        if path.contains("http") {
+            let mut ytdl_process = Command::new("yt-dlp")
+                .args(["-o", "-", path])
+                .stdout(Stdio::piped())
+                .stderr(direct_stderr())
+                .spawn()?;
+
+            let ffmpeg_args = vec![
+                "-ss", &start_str, "-i", "-",
+                "-f", "rawvideo", "-pix_fmt", "rgb24", "-vf", &filter_str, "-",
+            ];
+
+            let mut ffmpeg = Command::new("ffmpeg");
+            ffmpeg.args(&ffmpeg_args);
+            let audio_stream = if audio { add_audio_output(&mut ffmpeg).ok() } else { None };
+            let mut ffmpeg_process = ffmpeg
+                .stdin(Stdio::from(ytdl_process.stdout.take().ok_or("Couldn't get yt-dlp stdout")?))
+                .stdout(Stdio::piped())
+                .stderr(direct_stderr())
+                .spawn()?;
+
+            let stream = ffmpeg_process.stdout.take().ok_or("Couldn't get ffmpeg stdout")?;
+
+            Ok((
+                SourceStream::YouTube { ytdl: ytdl_process, ffmpeg: ffmpeg_process },
+                Some(Outputs { video: stream, audio: audio_stream }),
+            ))
+        } else {
+            let ffmpeg_args = vec![
+                "-ss", &start_str, "-i", path,
+                "-f", "rawvideo", "-pix_fmt", "rgb24", "-vf", &filter_str, "-",
+            ];
+
+            let mut ffmpeg = Command::new("ffmpeg");
+            ffmpeg.args(&ffmpeg_args);
+            let audio_stream = if audio { add_audio_output(&mut ffmpeg).ok() } else { None };
+            let mut ffmpeg_process = ffmpeg
+                .stdout(Stdio::piped())
+                // .stderr(direct_stderr())
+                .spawn()?;
+
+            let stream = ffmpeg_process.stdout.take().ok_or("Couldn't get ffmpeg stdout")?;
+
+            Ok((SourceStream::File { ffmpeg: ffmpeg_process }, Some(Outputs { video: stream, audio: audio_stream })))
+        }
+    }
+
     fn stop(&mut self) {
         match self {
             SourceStream::Blank => {}
             SourceStream::File { ffmpeg, .. } => {
-                ffmpeg.kill();
+                let _ = ffmpeg.kill();
             }
             SourceStream::YouTube { ytdl, ffmpeg, .. } => {
-                ytdl.kill();
-                ffmpeg.kill();
+                let _ = ytdl.kill();
+                let _ = ffmpeg.kill();
             }
         }
     }
+}
 
-    fn next_frame(&mut self, framerate: u32) -> Option<RgbImage> {
-        let stream = match self {
-            SourceStream::Blank => {
-                thread::sleep(Duration::from_secs_f32(1.0 / framerate as f32));
-                return Some(blank_frame());
-            },
-            SourceStream::File { stream, .. } => stream,
-            SourceStream::YouTube { stream, .. } => stream,
-        };
+impl Drop for SourceStream {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
 
-        let mut start: [u8; 6] = [0; 6];
-        match stream.read_exact(&mut start) {
-            Ok(_) => {}
-            // Err(_) => { break; }
-            Err(_) => {
-                return None;
-            }
-        }
-        let bmp_length = u32::from_le_bytes(start[2..6].try_into().unwrap());
-        let mut remaining_bytes: Vec<u8> = std::iter::repeat(0).take((bmp_length - 6) as usize).collect();
-        match stream.read_exact(&mut remaining_bytes[0..(bmp_length - 6) as usize]) {
-            Ok(_) => {}
-            // Err(_) => { break; }
-            Err(_) => {
-                return None;
+/// Where the output of a running ffmpeg comes out.
+struct Outputs {
+    /// Raw RGB frames.
+    video: ChildStdout,
+    /// Raw PCM, if the audio was asked for.
+    audio: Option<PipeReader>,
+}
+
+/// The file descriptor ffmpeg writes the audio to, as `pipe:3`.
+#[cfg(not(windows))]
+const AUDIO_FD: i32 = 3;
+
+/// Adds a second output to `ffmpeg` with the sound as raw PCM in the format the audio backends
+/// take, and returns the pipe it comes out of. The write end becomes descriptor `AUDIO_FD` in the
+/// child, and is closed here once the command is dropped.
+#[cfg(not(windows))]
+fn add_audio_output(ffmpeg: &mut Command) -> io::Result<PipeReader> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let (reader, writer) = io::pipe()?;
+    ffmpeg.args([
+        "-vn", "-f", "s16le",
+        "-ac", &CHANNELS.to_string(),
+        "-ar", &SAMPLE_RATE.to_string(),
+        &format!("pipe:{}", AUDIO_FD),
+    ]);
+    // SAFETY: only async-signal-safe functions are called between fork and exec
+    unsafe {
+        ffmpeg.pre_exec(move || {
+            let fd = writer.as_raw_fd();
+            // The copy made by dup2 stays open through exec, but if the pipe already is
+            // `AUDIO_FD` it has to be kept open by hand
+            let result = if fd == AUDIO_FD {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, AUDIO_FD)
+            };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
             }
-        }
-        let image_bytes: Vec<u8> = start.into_iter().chain(remaining_bytes.into_iter()).collect();
-        Some(ImageReader::with_format(Cursor::new(image_bytes), image::ImageFormat::Bmp)
-            .decode().unwrap().to_rgb8())
+            Ok(())
+        });
     }
+    Ok(reader)
 }
 
-impl Drop for SourceStream {
-    fn drop(&mut self) {
-        self.stop();
+#[cfg(windows)]
+fn add_audio_output(_ffmpeg: &mut Command) -> io::Result<PipeReader> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Audio isn't supported on Windows yet"))
+}
+
+/// What's known about a source before decoding it.
+#[derive(Clone)]
+struct Probe {
+    duration: Option<Duration>,
+    title: Option<String>,
+    /// The size of the video, which frames are decoded at.
+    size: (u32, u32),
+    /// Whether there's sound. ffmpeg won't start at all if it's asked to decode audio that
+    /// isn't there.
+    audio: bool,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self { duration: None, title: None, size: FALLBACK_SIZE, audio: false }
+    }
+}
+
+impl Probe {
+    fn new(path: &str) -> Self {
+        let output = if path.contains("http") {
+            Command::new("yt-dlp")
+                .args([
+                    "--print", "duration=%(duration)s",
+                    "--print", "title=%(title)s",
+                    "--print", "width=%(width)s",
+                    "--print", "height=%(height)s",
+                    "--print", "acodec=%(acodec)s",
+                    path,
+                ])
+                .stderr(Stdio::null())
+                .output()
+        } else {
+            Command::new("ffprobe")
+                .args([
+                    "-v", "error",
+                    "-show_entries", "format=duration:format_tags=title:stream=codec_type,width,height",
+                    "-of", "default=noprint_wrappers=1",
+                    path,
+                ])
+                .stderr(Stdio::null())
+                .output()
+        };
+        let Ok(output) = output else {
+            return Self::default();
+        };
+
+        // Both print one `key=value` line per field, and anything missing is left as is
+        let mut probe = Self::default();
+        let (mut width, mut height) = (None, None);
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            match line.trim().split_once('=') {
+                Some(("duration", value)) => {
+                    probe.duration = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                        .map(Duration::from_secs_f32);
+                }
+                // ffprobe prints tags with a prefix
+                Some(("title" | "TAG:title", value)) => {
+                    probe.title = Some(value.to_string()).filter(|title| !title.is_empty());
+                }
+                // Only the first video stream counts, which is the one ffmpeg picks
+                Some(("width", value)) if width.is_none() => width = value.parse::<u32>().ok().filter(|w| *w > 0),
+                Some(("height", value)) if height.is_none() => height = value.parse::<u32>().ok().filter(|h| *h > 0),
+                Some(("codec_type", "audio")) => probe.audio = true,
+                // yt-dlp says "none" for formats without sound
+                Some(("acodec", value)) => probe.audio = !value.is_empty() && value != "none" && value != "NA",
+                _ => {}
+            }
+        }
+        if let (Some(width), Some(height)) = (width, height) {
+            probe.size = (width, height);
+        }
+        probe
     }
 }
 
 fn blank_frame() -> RgbImage {
     RgbImage::from_pixel(1, 1, Rgb([0, 0, 0]))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::audio::AudioBackend;
+
+    /// Has ffmpeg make a 64x48 test video with sound that's `seconds` long, or returns `None` if
+    /// it can't.
+    fn test_video(name: &str, seconds: u32) -> Option<PathBuf> {
+        let video = std::env::temp_dir().join(format!("unicode_player_{}_{}.mkv", name, std::process::id()));
+        let made = Command::new("ffmpeg")
+            .args(["-v", "error", "-y", "-f", "lavfi", "-i"])
+            .arg(format!("testsrc=duration={}:size=64x48:rate=10", seconds))
+            .args(["-f", "lavfi", "-i"])
+            .arg(format!("sine=duration={}", seconds))
+            .arg("-shortest")
+            .arg(&video)
+            .status();
+        if !made.is_ok_and(|status| status.success()) {
+            eprintln!("Skipping the {} test, ffmpeg couldn't make a video to play", name);
+            return None;
+        }
+        Some(video)
+    }
+
+    #[test]
+    fn wav_audio_matches_duration() {
+        let Some(video) = test_video("audio", 1) else {
+            return;
+        };
+        let wav = std::env::temp_dir().join(format!("unicode_player_audio_{}.wav", std::process::id()));
+
+        let output = AudioOutput::new(AudioBackend::Wav, wav.clone());
+        let mut source = Source::new(video.to_str(), 10, false, Duration::ZERO, Some(&output)).unwrap();
+        while !source.finished {
+            source.next_frame();
+        }
+        let duration = source.duration().unwrap();
+        source.stop();
+
+        let bytes = fs::read(&wav).unwrap();
+        fs::remove_file(video).unwrap();
+        fs::remove_file(wav).unwrap();
+
+        let data_len = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        assert_eq!(bytes.len(), 44 + data_len as usize);
+        // Playback ends with the last video frame, so up to a frame of sound can be cut off
+        let samples = data_len as f64 / (CHANNELS as f64 * 2.0);
+        let expected = duration.as_secs_f64() * SAMPLE_RATE as f64;
+        assert!(
+            (samples - expected).abs() <= SAMPLE_RATE as f64 * 0.15,
+            "{} samples for {:?}",
+            samples,
+            duration
+        );
+    }
+
+    #[test]
+    fn resize_waits_for_size_to_hold() {
+        let Some(video) = test_video("resize", 10) else {
+            return;
+        };
+        let mut source = Source::new(video.to_str(), 10, false, Duration::ZERO, None).unwrap();
+
+        // The first size is used right away
+        source.set_frame_size((32, 24)).unwrap();
+        assert_eq!(source.size, (32, 24));
+
+        // Later ones wait, and each new one starts the wait over
+        source.set_frame_size((16, 12)).unwrap();
+        thread::sleep(RESIZE_DELAY / 2);
+        source.set_frame_size((20, 15)).unwrap();
+        thread::sleep(RESIZE_DELAY / 2);
+        source.set_frame_size((20, 15)).unwrap();
+        assert_eq!(source.size, (32, 24));
+        assert_eq!(source.next_frame().dimensions(), (32, 24));
+
+        thread::sleep(RESIZE_DELAY);
+        source.set_frame_size((20, 15)).unwrap();
+        assert_eq!(source.size, (20, 15));
+        assert_eq!(source.next_frame().dimensions(), (20, 15));
+
+        // Going back before it's applied leaves it as it is
+        source.set_frame_size((16, 12)).unwrap();
+        source.set_frame_size((20, 15)).unwrap();
+        thread::sleep(RESIZE_DELAY);
+        source.set_frame_size((20, 15)).unwrap();
+        assert_eq!(source.size, (20, 15));
+        assert!(source.pending_size.is_none());
+
+        source.stop();
+        fs::remove_file(video).unwrap();
+    }
+
+    #[test]
+    fn restart_keeps_frame_counts() {
+        let Some(video) = test_video("counts", 10) else {
+            return;
+        };
+        let mut source = Source::new(video.to_str(), 10, false, Duration::ZERO, None).unwrap();
+        source.frames_dropped = 3;
+        source.frames_repeated = 4;
+
+        source.seek(2.0).unwrap();
+        source.set_frame_size((32, 24)).unwrap();
+        assert_eq!((source.frames_dropped(), source.frames_repeated()), (3, 4));
+
+        source.stop();
+        fs::remove_file(video).unwrap();
+    }
+}
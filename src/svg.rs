@@ -0,0 +1,139 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::export::Exporter;
+use crate::html::{css_color, escape_xml, style_runs, CssPalette, DEFAULT_BG};
+use crate::recording::Header;
+use crate::screen::Frame;
+
+/// How wide each cell is drawn, in SVG user units. The height comes from the char height.
+const CELL_WIDTH: f32 = 8.0;
+
+/// Writes each frame to its own SVG file in a directory, named by frame number.
+pub(crate) struct SvgFramesWriter {
+    dir: PathBuf,
+    palette: CssPalette,
+    cell_height: f32,
+    frames: usize,
+}
+
+impl SvgFramesWriter {
+    pub(crate) fn create(path: &str, header: &Header) -> io::Result<Self> {
+        fs::create_dir_all(path)?;
+        Ok(Self {
+            dir: PathBuf::from(path),
+            palette: CssPalette::new(),
+            cell_height: CELL_WIDTH * header.char_height.unwrap_or(2.0),
+            frames: 0,
+        })
+    }
+
+    fn frame_svg(&self, frame: &Frame) -> String {
+        let width = frame.cells.iter().map(Vec::len).max().unwrap_or(0) as f32 * CELL_WIDTH;
+        let height = frame.cells.len() as f32 * self.cell_height;
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\" \
+            font-family=\"monospace\" font-size=\"{2}\">",
+            width,
+            height,
+            self.cell_height * 0.8,
+        ).unwrap();
+        writeln!(out, "<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>", css_color(DEFAULT_BG)).unwrap();
+
+        for (y, row) in frame.cells.iter().enumerate() {
+            let top = y as f32 * self.cell_height;
+            for run in style_runs(row, &self.palette) {
+                let left = run.x as f32 * CELL_WIDTH;
+                let run_width = run.width as f32 * CELL_WIDTH;
+                if run.bg != DEFAULT_BG {
+                    writeln!(
+                        out,
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                        left, top, run_width, self.cell_height, css_color(run.bg),
+                    ).unwrap();
+                }
+                if run.text.trim().is_empty() {
+                    continue;
+                }
+                // Fonts don't agree on how wide a character is, so the text is stretched to fit
+                writeln!(
+                    out,
+                    "<text x=\"{}\" y=\"{}\" textLength=\"{}\" lengthAdjust=\"spacingAndGlyphs\" fill=\"{}\"{} xml:space=\"preserve\">{}</text>",
+                    left,
+                    top + self.cell_height * 0.8,
+                    run_width,
+                    css_color(run.fg),
+                    if run.bold { " font-weight=\"bold\"" } else { "" },
+                    escape_xml(&run.text),
+                ).unwrap();
+            }
+        }
+
+        out.push_str("</svg>\n");
+        out
+    }
+}
+
+impl Exporter for SvgFramesWriter {
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        self.palette.update(&frame.prelude);
+        let path = self.dir.join(format!("{:05}.svg", self.frames));
+        self.frames += 1;
+        fs::write(path, self.frame_svg(&frame))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::TermColor;
+    use crate::screen::{text_cells, Cell};
+
+    #[test]
+    fn svg() {
+        let header = Header {
+            framerate: 10,
+            width: 3,
+            height: 2,
+            renderer: None,
+            color_depth: None,
+            char_height: Some(2.0),
+            title: None,
+            duration: None,
+            keyframe_interval: None,
+        };
+        let dir = std::env::temp_dir().join(format!("unicode_player_svg_{}", std::process::id()));
+        let writer = SvgFramesWriter::create(dir.to_str().unwrap(), &header).unwrap();
+        fs::remove_dir(dir).unwrap();
+
+        // A plain `a`, then a bold `<` on blue that takes up the rest of the row with a wide
+        // character. The blank row under them isn't drawn
+        let mut top = text_cells("a<界");
+        for cell in &mut top[1..] {
+            *cell = Cell { bg: Some(TermColor::Rgb([0, 0, 255])), ..cell.bold() };
+        }
+        let frame = Frame { cells: vec![top, text_cells("    ")], ..Frame::default() };
+
+        assert_eq!(
+            writer.frame_svg(&frame),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"32\" height=\"32\" viewBox=\"0 0 32 32\" \
+            font-family=\"monospace\" font-size=\"12.8\">\n\
+            <rect width=\"100%\" height=\"100%\" fill=\"#000000\"/>\n\
+            <text x=\"0\" y=\"12.8\" textLength=\"8\" lengthAdjust=\"spacingAndGlyphs\" fill=\"#e5e5e5\" \
+            xml:space=\"preserve\">a</text>\n\
+            <rect x=\"8\" y=\"0\" width=\"24\" height=\"16\" fill=\"#0000ff\"/>\n\
+            <text x=\"8\" y=\"12.8\" textLength=\"24\" lengthAdjust=\"spacingAndGlyphs\" fill=\"#e5e5e5\" \
+            font-weight=\"bold\" xml:space=\"preserve\">&lt;界</text>\n\
+            </svg>\n"
+        );
+    }
+}
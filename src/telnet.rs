@@ -0,0 +1,376 @@
+use std::error::Error;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::palette::ColorDepth;
+use crate::renderers::{RenderOptions, Renderer};
+use crate::screen::Screen;
+use crate::source::Source;
+use crate::terminal::TermEvent;
+use crate::tui::{clamp_client_size, EventResponse, Stats, Tui};
+
+// Telnet commands and options, from RFC 854 and the RFCs for each option
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const TERMINAL_TYPE: u8 = 24;
+const NAWS: u8 = 31;
+const TERMINAL_TYPE_IS: u8 = 0;
+const TERMINAL_TYPE_SEND: u8 = 1;
+
+/// How long a new client gets to send its size and terminal type before playback starts.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Used when the client won't say how big it is.
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// Plays `path` to everyone who connects on `port`. Each client gets its own source, so they can
+/// pause, seek and switch renderers on their own. Without a `color_depth`, it's picked from the
+/// terminal type each client sends.
+pub(crate) fn serve(
+    port: u16,
+    path: &str,
+    renderer: Renderer,
+    options: RenderOptions,
+    color_depth: Option<ColorDepth>,
+    framerate: u32,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    eprintln!("Playing {} over telnet on port {}", path, port);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let path = path.to_string();
+        thread::Builder::new()
+            .name("telnet client".to_string())
+            .spawn(move || {
+                // The client going away is the usual way for this to end, so errors aren't
+                // worth more than a note
+                if let Err(err) = play_to_client(stream, &path, renderer, options, color_depth, framerate) {
+                    eprintln!("Telnet client stopped: {}", err);
+                }
+            })?;
+    }
+    Ok(())
+}
+
+/// What the reading side of a connection has to tell the player.
+#[derive(Debug)]
+enum ClientInput {
+    Event(TermEvent),
+    Resize(u16, u16),
+    TerminalType(String),
+    /// Bytes to send back as part of option negotiation.
+    Reply(Vec<u8>),
+}
+
+fn play_to_client(
+    stream: TcpStream,
+    path: &str,
+    renderer: Renderer,
+    mut options: RenderOptions,
+    color_depth: Option<ColorDepth>,
+    framerate: u32,
+) -> Result<(), Box<dyn Error>> {
+    stream.set_nodelay(true)?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+
+    // Ask for character at a time input with no local echo, and for the size and terminal type
+    writer.write_all(&[
+        IAC, WILL, ECHO,
+        IAC, WILL, SUPPRESS_GO_AHEAD,
+        IAC, DO, NAWS,
+        IAC, DO, TERMINAL_TYPE,
+    ])?;
+    writer.flush()?;
+
+    let (tx, rx) = channel();
+    thread::Builder::new()
+        .name("telnet input".to_string())
+        .spawn(move || read_client(stream, tx))?;
+
+    // Without an answer, 16 colors is the safest guess
+    options.color_depth = color_depth.unwrap_or(ColorDepth::Ansi16);
+    let mut size = None;
+    let mut terminal_type = None;
+    let deadline = Instant::now() + NEGOTIATION_TIMEOUT;
+    while size.is_none() || terminal_type.is_none() {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ClientInput::Resize(width, height)) => size = Some((width, height)),
+            Ok(ClientInput::TerminalType(name)) => {
+                if color_depth.is_none() {
+                    options.color_depth = ColorDepth::from_term("", &name.to_lowercase());
+                }
+                terminal_type = Some(name);
+            }
+            Ok(ClientInput::Reply(bytes)) => {
+                writer.write_all(&bytes)?;
+                writer.flush()?;
+            }
+            Ok(ClientInput::Event(_)) => {}
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+    let mut size = size.unwrap_or(DEFAULT_SIZE);
+
+    let mut source = Source::new(Some(path), framerate, false, Duration::ZERO, None)?;
+    // Clients can't search, since that would run on the server
+    let mut tui = Tui::new(renderer, options, size).without_search();
+
+    // Switch to the alternate screen and hide the cursor
+    write!(writer, "\x1b[?1049h\x1b[?25l\x1b[2J")?;
+    let mut screen = Screen::new();
+    let mut stats = Stats::default();
+
+    'frame_loop: loop {
+        loop {
+            let input = match rx.try_recv() {
+                Ok(input) => input,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'frame_loop,
+            };
+            match input {
+                ClientInput::Event(event) => match tui.handle_event(event) {
+                    EventResponse::Quit => break 'frame_loop,
+                    EventResponse::Restart => {
+                        source.stop();
+                        source = Source::new(Some(path), framerate, false, Duration::ZERO, None)?;
+                    }
+                    EventResponse::PlayPause => source.toggle_pause(),
+                    EventResponse::Seek(offset) => source.seek(offset)?,
+                    // Searching is turned off, and there's no sound
+                    EventResponse::Ok | EventResponse::ChangeSource(_) | EventResponse::ChangeVolume(_) => {}
+                },
+                ClientInput::Resize(width, height) => {
+                    size = (width, height);
+                    write!(writer, "\x1b[2J")?;
+                    screen.invalidate();
+                }
+                // Playback has already started in whatever colors were picked
+                ClientInput::TerminalType(_) => {}
+                ClientInput::Reply(bytes) => writer.write_all(&bytes)?,
+            }
+        }
+
+        stats.position = source.position();
+        stats.duration = source.duration();
+        stats.frames_dropped = source.frames_dropped();
+        stats.frames_repeated = source.frames_repeated();
+        stats.queue_depth = source.queue_depth();
+
+        let video_size = source.video_size();
+        source.set_frame_size(tui.frame_size(video_size))?;
+        let img = source.next_frame();
+
+        let mut frame = tui.render(img, video_size, path, &stats, size);
+        // The player is laid out for terminals bigger than a lot of telnet clients start out at
+        frame.crop(size);
+        let output = screen.draw(frame);
+        stats.frame_bytes = output.len();
+
+        write!(writer, "{}\x1b[{};{}H", output, tui.cursor_y() + 1, tui.cursor_x() + 1)?;
+        writer.flush()?;
+    }
+
+    source.stop();
+    write!(writer, "\x1b[?25h\x1b[?1049l")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads from the client until it disconnects, turning what it sends into input for the player.
+fn read_client(mut stream: TcpStream, tx: Sender<ClientInput>) {
+    let mut parser = TelnetParser::default();
+    let mut buf = [0; 1024];
+    loop {
+        let read = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+        for input in parser.feed(&buf[..read]) {
+            if tx.send(input).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+enum ParserState {
+    #[default]
+    Data,
+    Iac,
+    /// After WILL, WONT, DO or DONT, waiting for the option.
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Splits what a client sends into keys and telnet commands.
+#[derive(Default)]
+struct TelnetParser {
+    state: ParserState,
+    subnegotiation: Vec<u8>,
+    /// Key presses that haven't been turned into events yet.
+    keys: Vec<u8>,
+}
+
+impl TelnetParser {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<ClientInput> {
+        let mut inputs = Vec::new();
+        for &byte in bytes {
+            self.state = match (&self.state, byte) {
+                (ParserState::Data, IAC) => ParserState::Iac,
+                (ParserState::Data, _) => {
+                    self.keys.push(byte);
+                    ParserState::Data
+                }
+                // An escaped 255 in the data
+                (ParserState::Iac, IAC) => {
+                    self.keys.push(byte);
+                    ParserState::Data
+                }
+                (ParserState::Iac, WILL | WONT | DO | DONT) => ParserState::Negotiation(byte),
+                (ParserState::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    ParserState::Subnegotiation
+                }
+                // Anything else, like go ahead or are you there, doesn't matter here
+                (ParserState::Iac, _) => ParserState::Data,
+                (ParserState::Negotiation(command), option) => {
+                    if let Some(reply) = negotiation_reply(*command, option) {
+                        inputs.push(ClientInput::Reply(reply));
+                    }
+                    ParserState::Data
+                }
+                (ParserState::Subnegotiation, IAC) => ParserState::SubnegotiationIac,
+                (ParserState::Subnegotiation, _) => {
+                    self.subnegotiation.push(byte);
+                    ParserState::Subnegotiation
+                }
+                (ParserState::SubnegotiationIac, SE) => {
+                    inputs.extend(parse_subnegotiation(&self.subnegotiation));
+                    ParserState::Data
+                }
+                (ParserState::SubnegotiationIac, _) => {
+                    self.subnegotiation.push(byte);
+                    ParserState::Subnegotiation
+                }
+            };
+        }
+
+        inputs.extend(TermEvent::from_bytes(&mut self.keys).into_iter().map(ClientInput::Event));
+        inputs
+    }
+}
+
+/// What to say back when the client offers or asks for an option. Options that were asked for
+/// already just get their answer, and anything else is turned down.
+fn negotiation_reply(command: u8, option: u8) -> Option<Vec<u8>> {
+    match (command, option) {
+        (WILL, TERMINAL_TYPE) => Some(vec![IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_SEND, IAC, SE]),
+        (WILL, NAWS) | (DO, ECHO | SUPPRESS_GO_AHEAD) => None,
+        (WILL, _) => Some(vec![IAC, DONT, option]),
+        (DO, _) => Some(vec![IAC, WONT, option]),
+        _ => None,
+    }
+}
+
+fn parse_subnegotiation(data: &[u8]) -> Option<ClientInput> {
+    match data {
+        [NAWS, w1, w0, h1, h0] => {
+            let size = clamp_client_size((u16::from_be_bytes([*w1, *w0]), u16::from_be_bytes([*h1, *h0])));
+            Some(ClientInput::Resize(size.0, size.1))
+        }
+        [TERMINAL_TYPE, TERMINAL_TYPE_IS, name @ ..] => {
+            Some(ClientInput::TerminalType(String::from_utf8_lossy(name).into_owned()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(parser: &mut TelnetParser, bytes: &[u8]) -> String {
+        format!("{:?}", parser.feed(bytes))
+    }
+
+    #[test]
+    fn keys_between_commands() {
+        let mut parser = TelnetParser::default();
+        // Go ahead and are you there are ignored
+        assert_eq!(
+            feed(&mut parser, &[b'p', IAC, 249, b'q', IAC, 246, b'\r', 0]),
+            "[Event(Char('p')), Event(Char('q')), Event(Enter)]"
+        );
+    }
+
+    #[test]
+    fn escaped_iac() {
+        let mut parser = TelnetParser::default();
+        // A doubled IAC is a 255 in the data, so the WILL after it isn't a command. Neither is UTF-8,
+        // so they're both dropped
+        assert_eq!(feed(&mut parser, &[IAC, IAC, WILL, b'q']), "[Event(Char('q'))]");
+        // Split across reads
+        assert_eq!(feed(&mut parser, &[b'a', IAC]), "[Event(Char('a'))]");
+        assert_eq!(feed(&mut parser, &[IAC, b'b']), "[Event(Char('b'))]");
+    }
+
+    #[test]
+    fn window_size() {
+        let mut parser = TelnetParser::default();
+        assert_eq!(feed(&mut parser, &[IAC, WILL, NAWS]), "[]");
+        assert_eq!(
+            feed(&mut parser, &[IAC, SB, NAWS, 0, 120, 0, 40, IAC, SE, b'x']),
+            "[Resize(120, 40), Event(Char('x'))]"
+        );
+        // A 255 in the size is doubled, and the subnegotiation can be split across reads
+        assert_eq!(feed(&mut parser, &[IAC, SB, NAWS, 1, IAC, IAC]), "[]");
+        assert_eq!(feed(&mut parser, &[0, 50, IAC, SE]), "[Resize(511, 50)]");
+    }
+
+    #[test]
+    fn window_size_limits() {
+        let mut parser = TelnetParser::default();
+        assert_eq!(
+            feed(&mut parser, &[IAC, SB, NAWS, IAC, IAC, IAC, IAC, IAC, IAC, IAC, IAC, IAC, SE]),
+            "[Resize(1000, 1000)]"
+        );
+        assert_eq!(feed(&mut parser, &[IAC, SB, NAWS, 0, 0, 0, 0, IAC, SE]), "[Resize(12, 6)]");
+        assert_eq!(feed(&mut parser, &[IAC, SB, NAWS, 0, 200, 0, 2, IAC, SE]), "[Resize(200, 6)]");
+    }
+
+    #[test]
+    fn terminal_type() {
+        let mut parser = TelnetParser::default();
+        assert_eq!(
+            feed(&mut parser, &[IAC, WILL, TERMINAL_TYPE]),
+            format!("{:?}", [ClientInput::Reply(vec![IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_SEND, IAC, SE])])
+        );
+        let mut reply = vec![IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_IS];
+        reply.extend_from_slice(b"XTERM-256COLOR");
+        reply.extend_from_slice(&[IAC, SE]);
+        assert_eq!(feed(&mut parser, &reply), "[TerminalType(\"XTERM-256COLOR\")]");
+    }
+
+    #[test]
+    fn refused_options() {
+        let mut parser = TelnetParser::default();
+        assert_eq!(
+            feed(&mut parser, &[IAC, WILL, 39, IAC, DO, 39, IAC, DO, ECHO, IAC, WONT, NAWS]),
+            format!("{:?}", [ClientInput::Reply(vec![IAC, DONT, 39]), ClientInput::Reply(vec![IAC, WONT, 39])])
+        );
+    }
+}
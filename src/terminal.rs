@@ -1,5 +1,7 @@
 use std::borrow::BorrowMut;
 use std::io::{stdin, Stdin, stdout, Stdout, Write};
+#[cfg(not(windows))]
+use std::time::{Duration, Instant};
 
 use crossterm::event::{
     Event as CtEvent, KeyCode as CtKeyCode, KeyEvent as CtKeyEvent, KeyEventKind as CtKeyEventKind,
@@ -68,6 +70,7 @@ impl Iterator for TermEventStream {
 pub(crate) struct Terminal<T: TermWrite> {
     pub(crate) kind: TermKind,
     writer: T,
+    synchronized_output: bool,
 }
 
 impl<T: TermWrite> Terminal<T> {
@@ -75,6 +78,7 @@ impl<T: TermWrite> Terminal<T> {
         Terminal {
             writer: TermUtility,
             kind: self.kind,
+            synchronized_output: self.synchronized_output,
         }
     }
 
@@ -97,16 +101,18 @@ impl<T: TermWrite> Terminal<T> {
 
 impl Terminal<TermWriter> {
     #[cfg(not(windows))]
+    #[allow(dead_code)]
     pub(crate) fn new_termion() -> Self {
+        let mut writer = stdout()
+            .into_alternate_screen()
+            .unwrap()
+            .into_raw_mode()
+            .unwrap();
+        let synchronized_output = query_synchronized_output(&mut writer);
         Self {
-            writer: TermWriter::Termion(
-                stdout()
-                    .into_alternate_screen()
-                    .unwrap()
-                    .into_raw_mode()
-                    .unwrap(),
-            ),
+            writer: TermWriter::Termion(writer),
             kind: TermKind::Termion,
+            synchronized_output,
         }
     }
 
@@ -114,10 +120,28 @@ impl Terminal<TermWriter> {
         let mut writer = stdout();
         crossterm::execute!(writer, crossterm::terminal::EnterAlternateScreen).unwrap();
         crossterm::terminal::enable_raw_mode().unwrap();
+        let synchronized_output = query_synchronized_output(&mut writer);
         Self {
             writer: TermWriter::Crossterm(writer),
             kind: TermKind::Crossterm,
+            synchronized_output,
+        }
+    }
+
+    /// Tells terminals that support it to hold off on drawing until the matching
+    /// `end_synchronized_update`, so frames don't show up half drawn.
+    pub(crate) fn begin_synchronized_update(&mut self) -> std::io::Result<()> {
+        if self.synchronized_output {
+            write!(self, "\x1b[?2026h")?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn end_synchronized_update(&mut self) -> std::io::Result<()> {
+        if self.synchronized_output {
+            write!(self, "\x1b[?2026l")?;
         }
+        Ok(())
     }
 
     pub(crate) fn move_cursor(&mut self, x: u16, y: u16) -> std::io::Result<()> {
@@ -135,6 +159,12 @@ impl Terminal<TermWriter> {
             TermWriter::Crossterm(w) => crossterm::queue!(w, crossterm::terminal::Clear(crossterm::terminal::ClearType::All)),
         }
     }
+
+    /// Puts back the default color palette after it's been replaced with OSC 4.
+    pub(crate) fn reset_palette(&mut self) -> std::io::Result<()> {
+        write!(self, "\x1b]104\x1b\\")?;
+        self.flush()
+    }
 }
 
 impl Write for Terminal<TermWriter> {
@@ -155,6 +185,55 @@ impl Write for Terminal<TermWriter> {
     }
 }
 
+/// Asks the terminal whether it supports synchronized output (DEC mode 2026) with DECRQM. This
+/// has to happen in raw mode, before anything else starts reading input. Device attributes are
+/// requested right after, since every terminal answers that, which marks the end of the reply.
+#[cfg(not(windows))]
+fn query_synchronized_output(writer: &mut impl Write) -> bool {
+    if write!(writer, "\x1b[?2026$p\x1b[c").and_then(|_| writer.flush()).is_err() {
+        return false;
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(500);
+    let mut reply = Vec::new();
+    // The device attributes reply ends in a `c`, which can't be part of the DECRQM reply
+    while reply.last() != Some(&b'c') {
+        let Some(byte) = read_stdin_byte(deadline) else {
+            return false;
+        };
+        reply.push(byte);
+    }
+    let reply = String::from_utf8_lossy(&reply);
+    // 1 and 2 mean the mode is set or reset, 3 that it's always on
+    ["1", "2", "3"]
+        .iter()
+        .any(|value| reply.contains(&format!("\x1b[?2026;{}$y", value)))
+}
+
+/// Reads one byte straight from the stdin file descriptor, giving up at `deadline`. This goes
+/// around the buffer in `Stdin` so nothing after the reply gets taken from the event reader, and
+/// nothing is left waiting on stdin once it gives up.
+#[cfg(not(windows))]
+fn read_stdin_byte(deadline: Instant) -> Option<u8> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    let mut poll_fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    // SAFETY: `poll_fd` is a single valid pollfd for the length of the call
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+    if ready <= 0 {
+        return None;
+    }
+
+    let mut byte = 0u8;
+    // SAFETY: reads at most one byte into `byte`
+    let read = unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) };
+    (read == 1).then_some(byte)
+}
+
+#[cfg(windows)]
+fn query_synchronized_output(_writer: &mut impl Write) -> bool {
+    false
+}
+
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum TermEvent {
     Char(char),
@@ -206,11 +285,68 @@ impl TermEvent {
         }
     }
 
+    /// Turns keys as a terminal sends them, like over telnet, into events. Anything at the end
+    /// that's only partly there, like half of a UTF-8 character or of an escape sequence, is left
+    /// in `input` for later.
+    pub(crate) fn from_bytes(input: &mut Vec<u8>) -> Vec<Self> {
+        let mut events = Vec::new();
+        let mut i = 0;
+        while i < input.len() {
+            let rest = &input[i..];
+            let (event, len) = match rest {
+                [0x1b, b'[' | b'O', b'A', ..] => (Some(Self::Up), 3),
+                [0x1b, b'[' | b'O', b'B', ..] => (Some(Self::Down), 3),
+                [0x1b, b'[' | b'O', b'C', ..] => (Some(Self::Right), 3),
+                [0x1b, b'[' | b'O', b'D', ..] => (Some(Self::Left), 3),
+                // The rest of the sequence can come with the next read
+                [0x1b] | [0x1b, b'[' | b'O'] => break,
+                // Other keys, like Home or F5, aren't used, but their sequence is skipped whole
+                [0x1b, b'O', _, ..] => (None, 3),
+                [0x1b, b'[', rest @ ..] => match rest.iter().position(|byte| !(0x20..0x40).contains(byte)) {
+                    Some(end) => (None, end + 3),
+                    None => break,
+                },
+                // Telnet sends return as CR LF or CR NUL
+                [b'\r', b'\n' | 0, ..] => (Some(Self::Enter), 2),
+                [b'\r' | b'\n', ..] => (Some(Self::Enter), 1),
+                [b'\t', ..] => (Some(Self::Tab), 1),
+                [0x7f | 0x08, ..] => (Some(Self::Backspace), 1),
+                [byte, ..] if *byte < 0x20 => (None, 1),
+                _ => {
+                    let len = utf8_len(rest[0]);
+                    if rest.len() < len {
+                        break;
+                    }
+                    match std::str::from_utf8(&rest[..len]) {
+                        Ok(c) => (c.chars().next().map(Self::Char), len),
+                        // Only the first byte is skipped, so nothing after it is lost
+                        Err(_) => (None, 1),
+                    }
+                }
+            };
+            events.extend(event);
+            i += len;
+        }
+        input.drain(..i);
+        events
+    }
+
     fn detect_quit(&self) -> bool {
         matches!(self, Self::Char('q'))
     }
 }
 
+/// How many bytes a UTF-8 character takes up, from its first byte.
+fn utf8_len(first: u8) -> usize {
+    match first {
+        0xf8.. => 1,
+        0xf0.. => 4,
+        0xe0.. => 3,
+        0xc0.. => 2,
+        _ => 1,
+    }
+}
+
 // impl TryFrom<TmEvent> for TermEvent {
 //     type Error = ();
 //
@@ -226,3 +362,51 @@ impl TermEvent {
 //         Self::from_crossterm(value).ok_or(())
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `reads` in one at a time, and writes out the events from each.
+    fn events(reads: &[&[u8]]) -> Vec<String> {
+        let mut input = Vec::new();
+        reads
+            .iter()
+            .map(|read| {
+                input.extend_from_slice(read);
+                format!("{:?}", TermEvent::from_bytes(&mut input))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            events(&[b"q \x1b[A\x1bOD\r\n\r\0\n\t\x7f\x08\x01"]),
+            ["[Char('q'), Char(' '), Up, Left, Enter, Enter, Enter, Tab, Backspace, Backspace]"]
+        );
+    }
+
+    #[test]
+    fn split_escape_sequence() {
+        assert_eq!(events(&[b"a\x1b", b"[", b"B"]), ["[Char('a')]", "[]", "[Down]"]);
+        assert_eq!(events(&[b"\x1b", b"OC"]), ["[]", "[Right]"]);
+        // An escape on its own is let go once something else comes
+        assert_eq!(events(&[b"\x1b", b"q"]), ["[]", "[Char('q')]"]);
+    }
+
+    #[test]
+    fn unused_escape_sequences() {
+        // Home, Delete, Ctrl+Up and F1
+        assert_eq!(events(&[b"\x1b[H\x1b[3~\x1b[1;5A\x1bOPx"]), ["[Char('x')]"]);
+        assert_eq!(events(&[b"\x1b[1;", b"5", b"Ay"]), ["[]", "[]", "[Char('y')]"]);
+    }
+
+    #[test]
+    fn split_utf8() {
+        let bytes = "é€".as_bytes();
+        assert_eq!(events(&[&bytes[..1], &bytes[1..3], &bytes[3..]]), ["[]", "[Char('é')]", "[Char('€')]"]);
+        // Bytes that can't start a character are skipped one at a time
+        assert_eq!(events(&[b"\xff\xbfq"]), ["[Char('q')]"]);
+    }
+}
@@ -1,58 +1,94 @@
 use std::iter;
 use std::time::Duration;
 
-use colored::Colorize;
 use image::RgbImage;
 use unicode_segmentation::UnicodeSegmentation;
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-use crate::terminal::{TermEvent, TermWrite, Terminal};
+use crate::renderers::RenderOptions;
+use crate::screen::{text_cells, Cell, Frame, Graphic, Grid};
+use crate::source::QUEUE_LENGTH;
+use crate::terminal::TermEvent;
 use crate::{youtube, Renderer, EVENT_THREAD_ACCEPT_EXIT};
 
 const HELP_TEXT: &str =
     "Press 'm'/'M' to cycle mode, 'q' to exit, 'r' to restart, 'p' to play/pause: ";
+const SEEK_HELP_TEXT: &str = "Press Left/Right to seek 5s, Down/Up to seek 30s";
+
+/// The rows under the player taken up by the info box.
+const INFO_HEIGHT: u32 = 8;
+
+/// The smallest the player gets, border included, however small the terminal is.
+const MIN_PLAYER_SIZE: (u32, u32) = (12, 6);
+
+/// The most columns or rows a terminal on the other end of a connection is taken to have, so one
+/// client can't have the server render something huge.
+pub(crate) const MAX_CLIENT_SIZE: u16 = 1000;
+
+/// Keeps a size that a client sent between the smallest player and `MAX_CLIENT_SIZE`.
+pub(crate) fn clamp_client_size(size: (u16, u16)) -> (u16, u16) {
+    (
+        size.0.clamp(MIN_PLAYER_SIZE.0 as u16, MAX_CLIENT_SIZE),
+        size.1.clamp(MIN_PLAYER_SIZE.1 as u16, MAX_CLIENT_SIZE),
+    )
+}
 
 pub(crate) struct Tui {
     player: Player,
     search: Search,
+    /// Searching runs yt-dlp where the player runs, which clients on a server shouldn't get to do.
+    search_enabled: bool,
     focus: TuiFocus,
     bounds: Area,
     cursor_pos: (u16, u16),
-    char_height: f32,
+    options: RenderOptions,
 }
 
 impl Tui {
     pub(crate) fn new(
         renderer: Renderer,
-        char_height: f32,
-        terminal: &Terminal<impl TermWrite>,
+        options: RenderOptions,
+        size: (u16, u16),
     ) -> Self {
         let mut tui = Self {
             player: Player::new(0, 0, renderer),
             search: Search::new(0, 0),
+            search_enabled: true,
             focus: TuiFocus::Player,
             bounds: Area {
                 width: 0,
                 height: 0,
             },
             cursor_pos: (0, 0),
-            char_height,
+            options,
         };
-        tui.update_size(terminal);
+        tui.update_size(size);
         tui
     }
 
-    pub(crate) fn update_size(&mut self, terminal: &Terminal<impl TermWrite>) {
-        let mut dims = terminal.size();
+    /// Leaves the search box out, for players that are shown to someone else.
+    pub(crate) fn without_search(mut self) -> Self {
+        self.search_enabled = false;
+        self.update_size((self.bounds.width as u16, self.bounds.height as u16));
+        self
+    }
+
+    /// Lays everything out again for a terminal that's `size` columns and rows.
+    pub(crate) fn update_size(&mut self, size: (u16, u16)) {
+        let mut dims = size;
         if dims.0 == 0 || dims.1 == 0 {
             dims = (80, 24)
         }
 
         self.bounds.width = dims.0 as u32;
         self.bounds.height = dims.1 as u32;
-        self.player
-            .update_size(self.bounds.width - 40, self.bounds.height - 5);
-        self.search.update_size(40, self.bounds.height);
+        let search_width = if self.search_enabled { 40 } else { 0 };
+        // Too small a terminal gets a frame that doesn't fit, rather than no player at all
+        self.player.update_size(
+            self.bounds.width.saturating_sub(search_width).max(MIN_PLAYER_SIZE.0),
+            self.bounds.height.saturating_sub(INFO_HEIGHT).max(MIN_PLAYER_SIZE.1),
+        );
+        self.search.update_size(search_width, self.bounds.height);
     }
 
     pub(crate) fn cursor_x(&self) -> u16 {
@@ -65,6 +101,9 @@ impl Tui {
 
     pub(crate) fn handle_event(&mut self, event: TermEvent) -> EventResponse {
         if matches!(event, TermEvent::Tab) {
+            if !self.search_enabled {
+                return EventResponse::Ok;
+            }
             self.focus = self.focus.next_focus();
             *EVENT_THREAD_ACCEPT_EXIT.lock().unwrap() = self.focus.should_exit();
             return EventResponse::Ok;
@@ -77,6 +116,12 @@ impl Tui {
                 TermEvent::Char('M') => self.player.last_renderer(),
                 TermEvent::Char('r') => return EventResponse::Restart,
                 TermEvent::Char('p') => return EventResponse::PlayPause,
+                TermEvent::Left => return EventResponse::Seek(-5.0),
+                TermEvent::Right => return EventResponse::Seek(5.0),
+                TermEvent::Down => return EventResponse::Seek(-30.0),
+                TermEvent::Up => return EventResponse::Seek(30.0),
+                TermEvent::Char('+') | TermEvent::Char('=') => return EventResponse::ChangeVolume(10),
+                TermEvent::Char('-') => return EventResponse::ChangeVolume(-10),
                 _ => {}
             },
             TuiFocus::Search => match event {
@@ -96,42 +141,118 @@ impl Tui {
         EventResponse::Ok
     }
 
+    /// The size the video should be scaled to for the player as it's currently laid out.
+    pub(crate) fn frame_size(&self, video_size: (u32, u32)) -> (u32, u32) {
+        self.player.renderer.frame_size(
+            video_size,
+            self.player.bounds.without_border(),
+            self.options.char_height,
+        )
+    }
+
     pub(crate) fn render(
         &mut self,
         img: &RgbImage,
+        video_size: (u32, u32),
         path: &str,
-        frame_time: Duration,
-        terminal: &Terminal<impl TermWrite>,
-    ) -> String {
-        self.update_size(terminal);
+        stats: &Stats,
+        size: (u16, u16),
+    ) -> Frame {
+        self.update_size(size);
 
-        let mut frame = self.player.render(img, self.char_height);
+        let frame = self.player.render(img, video_size, self.options);
+        self.layout(frame, self.player.renderer.name(), path, stats)
+    }
 
-        let renderer_name = self.player.renderer.name();
+    /// Shows a frame that was already rendered, like one from a recording, in the player.
+    pub(crate) fn render_recorded(
+        &mut self,
+        recorded: Frame,
+        renderer: Option<Renderer>,
+        path: &str,
+        stats: &Stats,
+        size: (u16, u16),
+    ) -> Frame {
+        self.update_size(size);
+
+        let frame = self.player.fit(recorded);
+        let renderer_name = renderer.map_or_else(
+            || "Recording".to_string(),
+            |renderer| format!("{} (recorded)", renderer.name()),
+        );
+        self.layout(frame, renderer_name, path, stats)
+    }
+
+    /// Puts the info box under the player, and the search box next to both.
+    fn layout(&mut self, mut frame: Frame, renderer_name: String, path: &str, stats: &Stats) -> Frame {
+        let renderer_str = format!(
+            " Current Renderer: {}, Frametime: {:?}, Output: {}/frame",
+            renderer_name,
+            stats.frame_time,
+            format_bytes(stats.frame_bytes),
+        );
 
-        let longest = (path.width_cjk() + 15)
-            .max(renderer_name.len() + 20)
+        let position = stats.position;
+        let position_str = format_timestamp(position);
+        let duration_str = stats.duration.map_or_else(|| "--:--".to_string(), format_timestamp);
+        let sync_str = format!(
+            " Vol ('+'/'-'): {}, Queue: {}/{}, Dropped: {}, Repeated: {}",
+            stats.volume.map_or_else(|| "--".to_string(), |volume| format!("{}%", volume)),
+            stats.queue_depth,
+            QUEUE_LENGTH,
+            stats.frames_dropped,
+            stats.frames_repeated,
+        );
+
+        let longest = (path.width() + 15)
+            .max(renderer_str.len() + 1)
+            .max(sync_str.len() + 1)
             .max(HELP_TEXT.len() + 3);
 
-        let info_spacer = " ".repeat(self.player.bounds.width as usize - (longest + 2));
+        let info_spacer = " ".repeat((self.player.bounds.width as usize).saturating_sub(longest + 2));
 
-        let frametime_str = format!("{:?}", frame_time);
+        let bar_width = longest - position_str.len() - duration_str.len() - 6;
+        let filled = match stats.duration {
+            Some(duration) if !duration.is_zero() => ((position.as_secs_f32()
+                / duration.as_secs_f32()
+                * bar_width as f32) as usize)
+                .min(bar_width),
+            _ => 0,
+        };
 
-        frame.extend(
+        frame.cells.extend(
             [
                 format!("╔{}╗{}", "═".repeat(longest), info_spacer),
                 format!(
                     "║ Now Playing: {}{}║{}",
                     path,
-                    " ".repeat(longest - path.width_cjk() - 14),
+                    " ".repeat(longest - path.width() - 14),
+                    info_spacer
+                ),
+                format!(
+                    "║ {} [{}{}] {} ║{}",
+                    position_str,
+                    "█".repeat(filled),
+                    "░".repeat(bar_width - filled),
+                    duration_str,
+                    info_spacer
+                ),
+                format!(
+                    "║{}{}║{}",
+                    renderer_str,
+                    " ".repeat(longest - renderer_str.len()),
+                    info_spacer
+                ),
+                format!(
+                    "║{}{}║{}",
+                    sync_str,
+                    " ".repeat(longest - sync_str.len()),
                     info_spacer
                 ),
                 format!(
-                    "║ Current Renderer: {}, Frametime: {}{}║{}",
-                    renderer_name,
-                    frametime_str,
-                    // " ".repeat(longest - renderer_name.len() - 19),
-                    " ".repeat(longest - renderer_name.len() - frametime_str.len() - 32),
+                    "║ {}{}║{}",
+                    SEEK_HELP_TEXT,
+                    " ".repeat(longest - SEEK_HELP_TEXT.len() - 1),
                     info_spacer
                 ),
                 format!(
@@ -142,22 +263,24 @@ impl Tui {
                 ),
                 format!("╚{}╝{}", "═".repeat(longest), info_spacer),
             ]
-            .into_iter(),
+            .map(|line| text_cells(&line)),
         );
 
-        for (line, search_line) in frame.iter_mut().zip(self.search.draw().iter()) {
-            line.push_str(search_line);
+        if self.search_enabled {
+            for (line, search_line) in frame.cells.iter_mut().zip(self.search.draw()) {
+                line.extend(search_line);
+            }
         }
 
         self.cursor_pos = match self.focus {
-            TuiFocus::Player => ((HELP_TEXT.len() + 3) as u16, (frame.len() - 1) as u16),
+            TuiFocus::Player => ((HELP_TEXT.len() + 3) as u16, (frame.cells.len() - 1) as u16),
             TuiFocus::Search => (
                 self.player.bounds.width as u16 + self.search.cursor_x(),
                 self.search.cursor_y(),
             ),
         };
 
-        frame.join("\r\n")
+        frame
     }
 }
 
@@ -190,6 +313,42 @@ pub(crate) enum EventResponse {
     Restart,
     ChangeSource(String),
     PlayPause,
+    Seek(f32),
+    ChangeVolume(i32),
+}
+
+/// Numbers about playback that are shown in the info box.
+#[derive(Clone, Default)]
+pub(crate) struct Stats {
+    pub(crate) frame_time: Duration,
+    pub(crate) position: Duration,
+    pub(crate) duration: Option<Duration>,
+    /// How much was written to the terminal for the last frame.
+    pub(crate) frame_bytes: usize,
+    pub(crate) volume: Option<u32>,
+    pub(crate) frames_dropped: u32,
+    pub(crate) frames_repeated: u32,
+    /// How many decoded frames were waiting to be shown.
+    pub(crate) queue_depth: usize,
+}
+
+fn format_bytes(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MiB", bytes as f32 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1} KiB", bytes as f32 / 1024.0)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+fn format_timestamp(time: Duration) -> String {
+    let secs = time.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -237,17 +396,52 @@ impl Player {
         self.renderer = self.renderer.last_mode();
     }
 
-    fn render(&self, img: &RgbImage, char_height: f32) -> Vec<String> {
+    fn render(&self, img: &RgbImage, video_size: (u32, u32), options: RenderOptions) -> Frame {
+        let frame = self.renderer.render_player(img, video_size, self.bounds.without_border(), options);
+        self.with_border(frame)
+    }
+
+    /// Crops or pads a frame that was rendered for some other size to fit inside the border.
+    fn fit(&self, mut frame: Frame) -> Frame {
         let inner_bounds = self.bounds.without_border();
-        let frame = self.renderer.render_player(img, inner_bounds, char_height);
+        frame.cells.resize(inner_bounds.height as usize, Vec::new());
+        for row in &mut frame.cells {
+            row.resize(inner_bounds.width as usize, Cell::BLANK);
+            // Don't leave half of a wide character at the edge
+            if row.last().is_some_and(|cell| cell.ch.width() == Some(2)) {
+                *row.last_mut().unwrap() = Cell::BLANK;
+            }
+        }
+        self.with_border(frame)
+    }
 
-        iter::once(format!("╭{}╮", "─".repeat(inner_bounds.width as usize)))
-            .chain(frame.into_iter().map(|line| format!("│{}│", line)))
-            .chain(iter::once(format!(
+    fn with_border(&self, frame: Frame) -> Frame {
+        let inner_bounds = self.bounds.without_border();
+
+        let cells = iter::once(text_cells(&format!("╭{}╮", "─".repeat(inner_bounds.width as usize))))
+            .chain(frame.cells.into_iter().map(|line| {
+                iter::once(Cell::plain('│'))
+                    .chain(line)
+                    .chain(iter::once(Cell::plain('│')))
+                    .collect()
+            }))
+            .chain(iter::once(text_cells(&format!(
                 "╰{}╯",
                 "─".repeat(inner_bounds.width as usize)
-            )))
-            .collect()
+            ))))
+            .collect();
+
+        let graphics = frame
+            .graphics
+            .into_iter()
+            .map(|graphic| Graphic {
+                x: graphic.x + 1,
+                y: graphic.y + 1,
+                ..graphic
+            })
+            .collect();
+
+        Frame { prelude: frame.prelude, cells, graphics }
     }
 }
 
@@ -341,7 +535,7 @@ impl Search {
         None
     }
 
-    fn draw(&self) -> Vec<String> {
+    fn draw(&self) -> Grid {
         let query_box_width = self.bounds.width as isize - 13;
 
         let truncated_query = self
@@ -355,7 +549,7 @@ impl Search {
 
         let mut results = self.results.iter();
 
-        let rendered_results: Vec<String> = if self.results.is_empty() {
+        let rendered_results: Grid = if self.results.is_empty() {
             None
         } else {
             // We just checked that the results vec is not empty
@@ -378,28 +572,30 @@ impl Search {
 
         let rendered_results_len = rendered_results.len();
 
-        iter::once(format!(
+        iter::once(text_cells(&format!(
             "╔════════╦{}╗",
             "═".repeat(self.bounds.width as usize - 11)
-        ))
-        .chain(iter::once(format!(
+        )))
+        .chain(iter::once(text_cells(&format!(
             "║ Search ║ {}{} ║",
             truncated_query,
             " ".repeat(query_box_width as usize - truncated_query.len()),
-        )))
-        .chain(iter::once(format!(
+        ))))
+        .chain(iter::once(text_cells(&format!(
             "╠════════╩{}╣",
             "═".repeat(self.bounds.width as usize - 11)
-        )))
-        .chain(rendered_results.into_iter())
+        ))))
+        .chain(rendered_results)
         .chain(
-            iter::repeat(format!("║{}║", " ".repeat(self.bounds.width as usize - 2)))
-                .take(self.bounds.height as usize - 4 - rendered_results_len),
+            iter::repeat_n(
+                text_cells(&format!("║{}║", " ".repeat(self.bounds.width as usize - 2))),
+                self.bounds.height as usize - 4 - rendered_results_len,
+            ),
         )
-        .chain(iter::once(format!(
+        .chain(iter::once(text_cells(&format!(
             "╚{}╝",
             "═".repeat(self.bounds.width as usize - 2)
-        )))
+        ))))
         .collect()
     }
 }
@@ -413,11 +609,11 @@ pub(crate) struct SearchResult {
 }
 
 impl SearchResult {
-    fn draw(&self, width: u32, is_first: bool) -> Vec<String> {
-        let mut frame: Vec<String> = if is_first {
+    fn draw(&self, width: u32, is_first: bool) -> Grid {
+        let mut frame: Grid = if is_first {
             Vec::new()
         } else {
-            vec![format!("╟{}╢", "─".repeat(width as usize - 2))]
+            vec![text_cells(&format!("╟{}╢", "─".repeat(width as usize - 2)))]
         };
 
         let display_area = width as usize - 4;
@@ -426,7 +622,7 @@ impl SearchResult {
             .title
             .graphemes(true)
             .scan(0_usize, |width, grapheme| {
-                *width += grapheme.width_cjk();
+                *width += grapheme.width();
                 if *width > display_area {
                     None
                 } else {
@@ -438,7 +634,7 @@ impl SearchResult {
             .uploader
             .graphemes(true)
             .scan(0_usize, |width, grapheme| {
-                *width += grapheme.width_cjk();
+                *width += grapheme.width();
                 if *width > display_area {
                     None
                 } else {
@@ -454,17 +650,22 @@ impl SearchResult {
         //     .unwrap();
         // writeln!(f, "{:?}", self).unwrap();
 
-        frame.push(format!(
-            "║ {}{} ║",
-            title_string.bold(),
-            " ".repeat(display_area - title_string.width_cjk())
-        ));
+        frame.push(
+            text_cells("║ ")
+                .into_iter()
+                .chain(text_cells(&title_string).into_iter().map(Cell::bold))
+                .chain(text_cells(&format!(
+                    "{} ║",
+                    " ".repeat(display_area - title_string.width())
+                )))
+                .collect(),
+        );
 
-        frame.push(format!(
+        frame.push(text_cells(&format!(
             "║ {}{} ║",
             uploader_string,
-            " ".repeat(display_area - uploader_string.width_cjk())
-        ));
+            " ".repeat(display_area - uploader_string.width())
+        )));
 
         frame
     }
@@ -0,0 +1,535 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine;
+use image::RgbImage;
+use serde::Deserialize;
+
+use crate::http::{self, MAX_REQUEST_SIZE};
+use crate::palette::ColorDepth;
+use crate::renderers::{RenderOptions, Renderer};
+use crate::screen::Screen;
+use crate::source::Source;
+use crate::terminal::TermEvent;
+use crate::tui::{clamp_client_size, EventResponse, Stats, Tui};
+
+const PAGE: &str = include_str!("web_player.html");
+
+/// Where the page's copy of xterm.js is served from. See the README in there.
+const XTERM_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/vendor/xterm");
+
+/// Goes into the WebSocket handshake, from RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// The most a viewer can send in one message. They only ever send keys and their size.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// The largest the video is decoded at. Every viewer scales it down again for their own renderer
+/// and size, so there's no point in decoding more than a big terminal can show.
+const MAX_DECODE_SIZE: (u32, u32) = (960, 540);
+
+/// How long a viewer waits for a new frame before checking for input anyway, like while paused.
+const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Used until the page says how big the terminal is.
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// How long to wait before trying again when the video can't be reopened.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Plays `path` once, and streams it to every browser that opens the page on `port`. Each viewer
+/// renders the shared frames at their own size and with their own renderer, but pausing and
+/// seeking are shared.
+pub(crate) fn serve(
+    port: u16,
+    path: &str,
+    renderer: Renderer,
+    mut options: RenderOptions,
+    framerate: u32,
+) -> io::Result<()> {
+    // xterm.js draws every color there is
+    options.color_depth = ColorDepth::Truecolor;
+
+    let source = open_source(path, framerate).map_err(|err| io::Error::other(err.to_string()))?;
+    let stream = Arc::new(SharedStream::new(source.title().unwrap_or(path)));
+    let (controls_tx, controls_rx) = channel();
+    thread::Builder::new().name("web stream".to_string()).spawn({
+        let stream = stream.clone();
+        let path = path.to_string();
+        move || decode(source, &path, framerate, &stream, controls_rx)
+    })?;
+
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    eprintln!("Playing {} on http://localhost:{}/", path, port);
+
+    for connection in listener.incoming() {
+        let connection = connection?;
+        let stream = stream.clone();
+        let controls = controls_tx.clone();
+        thread::Builder::new()
+            .name("web viewer".to_string())
+            .spawn(move || {
+                if let Err(err) = handle_client(connection, &stream, controls, renderer, options) {
+                    eprintln!("Web viewer stopped: {}", err);
+                }
+            })?;
+    }
+    Ok(())
+}
+
+/// The latest frame of the video, which every viewer draws from.
+struct SharedStream {
+    state: Mutex<StreamState>,
+    new_frame: Condvar,
+}
+
+struct StreamState {
+    /// Goes up with every frame, so viewers can tell when there's a new one.
+    count: u64,
+    image: Arc<RgbImage>,
+    video_size: (u32, u32),
+    title: String,
+    stats: Stats,
+}
+
+impl SharedStream {
+    fn new(title: &str) -> Self {
+        Self {
+            state: Mutex::new(StreamState {
+                count: 0,
+                image: Arc::new(RgbImage::new(1, 1)),
+                video_size: (1, 1),
+                title: title.to_string(),
+                stats: Stats::default(),
+            }),
+            new_frame: Condvar::new(),
+        }
+    }
+}
+
+fn open_source(path: &str, framerate: u32) -> Result<Source, Box<dyn Error>> {
+    let mut source = Source::new(Some(path), framerate, false, Duration::ZERO, None)?;
+    let (width, height) = source.video_size();
+    let scale = (MAX_DECODE_SIZE.0 as f32 / width as f32)
+        .min(MAX_DECODE_SIZE.1 as f32 / height as f32)
+        .min(1.0);
+    source.set_frame_size(((width as f32 * scale) as u32, (height as f32 * scale) as u32))?;
+    Ok(source)
+}
+
+/// Starts the video over, trying again until it opens, since the viewers have nothing to
+/// watch without it.
+fn reopen_source(source: &mut Source, path: &str, framerate: u32) {
+    source.stop();
+    loop {
+        match open_source(path, framerate) {
+            Ok(reopened) => {
+                *source = reopened;
+                return;
+            }
+            Err(err) => {
+                eprintln!("Couldn't reopen {}: {}", path, err);
+                thread::sleep(REOPEN_DELAY);
+            }
+        }
+    }
+}
+
+/// Decodes the video for everyone, starting over whenever it ends. Viewers send what they want
+/// done to it through `controls`.
+fn decode(mut source: Source, path: &str, framerate: u32, stream: &SharedStream, controls: Receiver<EventResponse>) {
+    loop {
+        for control in controls.try_iter() {
+            match control {
+                EventResponse::Restart => reopen_source(&mut source, path, framerate),
+                EventResponse::PlayPause => source.toggle_pause(),
+                EventResponse::Seek(offset) => {
+                    // A failed seek leaves the source stopped
+                    if let Err(err) = source.seek(offset) {
+                        eprintln!("Couldn't seek in {}: {}", path, err);
+                        reopen_source(&mut source, path, framerate);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if source.finished {
+            reopen_source(&mut source, path, framerate);
+        }
+
+        let image = Arc::new(source.next_frame().clone());
+
+        let mut state = stream.state.lock().unwrap();
+        state.count += 1;
+        state.image = image;
+        state.video_size = source.video_size();
+        state.stats.position = source.position();
+        state.stats.duration = source.duration();
+        state.stats.frames_dropped = source.frames_dropped();
+        state.stats.frames_repeated = source.frames_repeated();
+        state.stats.queue_depth = source.queue_depth();
+        drop(state);
+        stream.new_frame.notify_all();
+    }
+}
+
+/// Serves the page and its files, or starts streaming to a viewer if it's the page's WebSocket.
+fn handle_client(
+    connection: TcpStream,
+    stream: &SharedStream,
+    controls: Sender<EventResponse>,
+    renderer: Renderer,
+    options: RenderOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(connection.try_clone()?);
+    let mut out = BufWriter::new(connection);
+
+    let Some(request) = http::read_request(&mut (&mut reader).take(MAX_REQUEST_SIZE))? else {
+        return http::send_text(&mut out, "400 Bad Request", "That doesn't look like an HTTP request.\n");
+    };
+    if request.method != "GET" {
+        return http::send_text(&mut out, "405 Method Not Allowed", "Only GET works here.\n");
+    }
+
+    match request.path.as_str() {
+        "/" => http::send(&mut out, "200 OK", "text/html; charset=utf-8", PAGE.as_bytes()),
+        "/xterm.js" => send_vendored(&mut out, "xterm.js", "text/javascript"),
+        "/xterm.css" => send_vendored(&mut out, "xterm.css", "text/css"),
+        "/stream" if request.header("Upgrade").eq_ignore_ascii_case("websocket") => {
+            let key = request.header("Sec-WebSocket-Key");
+            if key.is_empty() {
+                return http::send_text(&mut out, "400 Bad Request", "The WebSocket key is missing.\n");
+            }
+            write!(
+                out,
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            )?;
+            out.flush()?;
+            watch(reader, out, stream, controls, renderer, options)
+        }
+        _ => http::send_text(&mut out, "404 Not Found", "Not found.\n"),
+    }
+}
+
+/// What the server answers a `Sec-WebSocket-Key` with, to show it knows it's talking WebSocket.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.digest().bytes())
+}
+
+fn send_vendored(out: &mut impl Write, name: &str, content_type: &str) -> Result<(), Box<dyn Error>> {
+    // Without a copy here, the page falls back to a CDN
+    match fs::read(Path::new(XTERM_DIR).join(name)) {
+        Ok(file) => http::send(out, "200 OK", content_type, &file),
+        Err(_) => http::send_text(out, "404 Not Found", "xterm.js isn't in vendor/xterm.\n"),
+    }
+}
+
+/// What a viewer's page sends, as JSON in text messages.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Message {
+    /// Keys, the way a terminal sends them.
+    Input(String),
+    /// The columns and rows of the terminal on the page.
+    Resize(u16, u16),
+}
+
+/// What the reading side of a WebSocket has to tell the viewer.
+enum ViewerInput {
+    Event(TermEvent),
+    Resize(u16, u16),
+    /// A message to send back, like the answer to a ping.
+    Reply(u8, Vec<u8>),
+}
+
+/// Draws the shared stream for one viewer until they leave.
+fn watch(
+    reader: BufReader<TcpStream>,
+    mut out: BufWriter<TcpStream>,
+    stream: &SharedStream,
+    controls: Sender<EventResponse>,
+    renderer: Renderer,
+    options: RenderOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = channel();
+    thread::Builder::new()
+        .name("web viewer input".to_string())
+        .spawn(move || read_viewer(reader, tx))?;
+
+    let mut size = DEFAULT_SIZE;
+    // Searching would run on the server
+    let mut tui = Tui::new(renderer, options, size).without_search();
+    let mut screen = Screen::new();
+    let mut frame_bytes = 0;
+    let mut last_count = 0;
+
+    write_message(&mut out, OPCODE_TEXT, b"\x1b[?25l\x1b[2J")?;
+
+    'frame_loop: loop {
+        loop {
+            let input = match rx.try_recv() {
+                Ok(input) => input,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'frame_loop,
+            };
+            match input {
+                ViewerInput::Event(event) => match tui.handle_event(event) {
+                    EventResponse::Quit => break 'frame_loop,
+                    // Everyone watches the same stream, so this is up to whoever gets there first
+                    response @ (EventResponse::Restart | EventResponse::PlayPause | EventResponse::Seek(_)) => {
+                        // The stream only stops along with the whole server
+                        let _ = controls.send(response);
+                    }
+                    // Searching is turned off, and there's no sound
+                    EventResponse::Ok | EventResponse::ChangeSource(_) | EventResponse::ChangeVolume(_) => {}
+                },
+                ViewerInput::Resize(width, height) => {
+                    size = (width, height);
+                    screen.invalidate();
+                    write_message(&mut out, OPCODE_TEXT, b"\x1b[2J")?;
+                }
+                ViewerInput::Reply(opcode, data) => write_message(&mut out, opcode, &data)?,
+            }
+        }
+
+        let state = stream.state.lock().unwrap();
+        let (state, _) = stream
+            .new_frame
+            .wait_timeout_while(state, FRAME_TIMEOUT, |state| state.count == last_count)
+            .unwrap();
+        last_count = state.count;
+        let image = state.image.clone();
+        let video_size = state.video_size;
+        let title = state.title.clone();
+        let stats = Stats { frame_bytes, ..state.stats.clone() };
+        drop(state);
+
+        let mut frame = tui.render(&image, video_size, &title, &stats, size);
+        // The player is laid out for terminals bigger than a small browser window
+        frame.crop(size);
+        let output = format!(
+            "{}\x1b[{};{}H",
+            screen.draw(frame),
+            tui.cursor_y() + 1,
+            tui.cursor_x() + 1
+        );
+        frame_bytes = output.len();
+        write_message(&mut out, OPCODE_TEXT, output.as_bytes())?;
+    }
+
+    // The viewer might be gone already
+    let _ = write_message(&mut out, OPCODE_CLOSE, &[]);
+    Ok(())
+}
+
+/// Reads messages from a viewer until they close the connection or it drops.
+fn read_viewer(mut reader: BufReader<TcpStream>, tx: Sender<ViewerInput>) {
+    let mut keys = Vec::new();
+    let mut partial = PartialMessage::default();
+    loop {
+        let inputs = match read_message(&mut reader, &mut partial) {
+            Ok((OPCODE_TEXT, data)) => match serde_json::from_slice(&data) {
+                Ok(Message::Input(input)) => {
+                    keys.extend_from_slice(input.as_bytes());
+                    TermEvent::from_bytes(&mut keys).into_iter().map(ViewerInput::Event).collect()
+                }
+                Ok(Message::Resize(width, height)) => {
+                    let (width, height) = clamp_client_size((width, height));
+                    vec![ViewerInput::Resize(width, height)]
+                }
+                // Anything else is from something that isn't the page
+                Err(_) => Vec::new(),
+            },
+            Ok((OPCODE_PING, data)) => vec![ViewerInput::Reply(OPCODE_PONG, data)],
+            Ok((OPCODE_CLOSE, _)) | Err(_) => return,
+            Ok(_) => Vec::new(),
+        };
+        for input in inputs {
+            if tx.send(input).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The frames of a message that have come in so far, and the opcode from the first of them.
+#[derive(Default)]
+struct PartialMessage {
+    opcode: Option<u8>,
+    data: Vec<u8>,
+}
+
+/// Reads the next whole message, putting it back together if it was split into several frames.
+/// Pings and other control frames can come in between those, and are returned as they arrive,
+/// with the rest of the message kept in `partial` for the next call.
+fn read_message(reader: &mut impl Read, partial: &mut PartialMessage) -> io::Result<(u8, Vec<u8>)> {
+    loop {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if partial.data.len() as u64 + len > MAX_MESSAGE_SIZE as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "WebSocket message is too large"));
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut data = vec![0; len as usize];
+        reader.read_exact(&mut data)?;
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        match opcode {
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => return Ok((opcode, data)),
+            OPCODE_CONTINUATION => partial.data.extend(data),
+            OPCODE_TEXT | OPCODE_BINARY => {
+                partial.opcode = Some(opcode);
+                partial.data = data;
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown WebSocket opcode")),
+        }
+        if fin {
+            let data = std::mem::take(&mut partial.data);
+            // Continuations without a first frame are dropped
+            if let Some(opcode) = partial.opcode.take() {
+                return Ok((opcode, data));
+            }
+        }
+    }
+}
+
+/// Sends `data` as one unmasked frame, which is how servers send everything.
+fn write_message(out: &mut impl Write, opcode: u8, data: &[u8]) -> io::Result<()> {
+    out.write_all(&[0x80 | opcode])?;
+    match data.len() {
+        len @ 0..=125 => out.write_all(&[len as u8])?,
+        len @ 126..=0xffff => {
+            out.write_all(&[126])?;
+            out.write_all(&(len as u16).to_be_bytes())?;
+        }
+        len => {
+            out.write_all(&[127])?;
+            out.write_all(&(len as u64).to_be_bytes())?;
+        }
+    }
+    out.write_all(data)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(mut reader: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        read_message(&mut reader, &mut PartialMessage::default())
+    }
+
+    fn written(opcode: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_message(&mut out, opcode, data).unwrap();
+        out
+    }
+
+    #[test]
+    fn handshake() {
+        // The example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn masked_message() {
+        // "Hello" as a client sends it, also from RFC 6455
+        let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(read(&frame).unwrap(), (OPCODE_TEXT, b"Hello".to_vec()));
+    }
+
+    #[test]
+    fn split_message() {
+        // A ping in the middle of a message comes out first
+        let frames = [
+            &[0x01, 0x03][..], b"Hel",
+            &[0x89, 0x02], b"hi",
+            &[0x80, 0x02], b"lo",
+        ]
+        .concat();
+        let mut reader = &frames[..];
+        let mut partial = PartialMessage::default();
+        assert_eq!(read_message(&mut reader, &mut partial).unwrap(), (OPCODE_PING, b"hi".to_vec()));
+        assert_eq!(read_message(&mut reader, &mut partial).unwrap(), (OPCODE_TEXT, b"Hello".to_vec()));
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn lengths() {
+        let short = written(OPCODE_TEXT, b"Hello");
+        assert_eq!(short, [&[0x81, 0x05][..], b"Hello"].concat());
+
+        let data = (0..=255).collect::<Vec<u8>>();
+        let medium = written(OPCODE_BINARY, &data);
+        assert_eq!(medium[..4], [0x82, 126, 0x01, 0x00]);
+        assert_eq!(read(&medium).unwrap(), (OPCODE_BINARY, data));
+
+        let data = vec![b'x'; MAX_MESSAGE_SIZE];
+        let long = written(OPCODE_TEXT, &data);
+        assert_eq!(long[..10], [0x81, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]);
+        assert_eq!(read(&long).unwrap(), (OPCODE_TEXT, data));
+    }
+
+    #[test]
+    fn too_long() {
+        let long = written(OPCODE_TEXT, &vec![b'x'; MAX_MESSAGE_SIZE + 1]);
+        assert_eq!(read(&long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Only the length is looked at, so a huge one doesn't get allocated
+        let huge = [0x82, 127, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(read(&huge).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn close() {
+        let close = written(OPCODE_CLOSE, &[]);
+        assert_eq!(close, [0x88, 0x00]);
+        assert_eq!(read(&close).unwrap(), (OPCODE_CLOSE, Vec::new()));
+
+        // A client's close with status 1000, masked with zeros
+        let frame = [0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8];
+        assert_eq!(read(&frame).unwrap(), (OPCODE_CLOSE, vec![0x03, 0xe8]));
+    }
+}
@@ -35,7 +35,7 @@ pub(crate) fn search(query: &str) -> Result<Vec<SearchResult>, Box<dyn std::erro
                 .get("title")?
                 .get("runs")?
                 .as_array()?
-                .get(0)?
+                .first()?
                 .get("text")?
                 .as_str()?
                 .to_string(),
@@ -43,7 +43,7 @@ pub(crate) fn search(query: &str) -> Result<Vec<SearchResult>, Box<dyn std::erro
                 .get("ownerText")?
                 .get("runs")?
                 .as_array()?
-                .get(0)?
+                .first()?
                 .get("text")?
                 .as_str()?
                 .to_string(),
@@ -73,7 +73,7 @@ pub(crate) fn search(query: &str) -> Result<Vec<SearchResult>, Box<dyn std::erro
         .ok_or("contents (1) not found")?
         .as_array()
         .ok_or("not an array (0)")?
-        .get(0)
+        .first()
         .ok_or("no elements in array")?
         .get("itemSectionRenderer")
         .ok_or("itemSectionRenderer not found")?